@@ -0,0 +1,117 @@
+use crate::mapping::CommandSpec;
+use anyhow::{bail, Context, Result};
+use std::ffi::{CStr, CString};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+/// The identity of a user that a command can be run as
+struct UserInfo {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    /// The supplementary groups of the user, including `gid`
+    groups: Vec<libc::gid_t>,
+    home: String,
+}
+
+impl UserInfo {
+    fn with_name(name: &str) -> Result<Self> {
+        let c_name = CString::new(name).context("user name contains a NUL byte")?;
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let mut result = std::ptr::null_mut();
+        let err = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if err != 0 {
+            return Err(std::io::Error::from_raw_os_error(err))
+                .context(format!("looking up user `{name}`"));
+        }
+        if result.is_null() {
+            bail!("No such user `{name}`");
+        }
+        let home = unsafe { CStr::from_ptr(pwd.pw_dir) }
+            .to_string_lossy()
+            .into_owned();
+        let groups = group_list(&c_name, pwd.pw_gid)?;
+        Ok(Self {
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+            groups,
+            home,
+        })
+    }
+}
+
+/// Returns the groups that `name` is a member of, along with `gid`
+fn group_list(name: &CStr, gid: libc::gid_t) -> Result<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let result =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if result >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // `count` has been set to the number of groups that there are
+        let needed = (count as usize).max(groups.len() * 2);
+        if needed > 65536 {
+            bail!("looking up the groups of user `{}`", name.to_string_lossy());
+        }
+        groups.resize(needed, 0);
+    }
+}
+
+/// Start the program described by `spec` without waiting for it
+/// to complete.  The child is reaped by a background thread so
+/// that it doesn't linger as a zombie.
+pub fn spawn_command(spec: &CommandSpec) -> Result<()> {
+    let mut cmd = Command::new(&spec.program);
+    cmd.args(&spec.args).stdin(Stdio::null());
+
+    if let Some(user) = &spec.user {
+        let info = UserInfo::with_name(user)?;
+        // These are only defaults; `env` can still override them
+        cmd.env("HOME", &info.home)
+            .env("USER", user)
+            .env("LOGNAME", user);
+
+        // std would drop all supplementary groups if we used its
+        // uid/gid methods, so switch identity ourselves in order to
+        // pick up the groups of the target user.  The groups were
+        // looked up beforehand, as only async-signal-safe functions
+        // may be called between fork and exec.
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setgroups(info.groups.len(), info.groups.as_ptr()) != 0
+                    || libc::setgid(info.gid) != 0
+                    || libc::setuid(info.uid) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    cmd.envs(&spec.env);
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("spawning {:?} {:?}", spec.program, spec.args))?;
+    log::debug!("spawned {} as pid {}", spec.program, child.id());
+
+    let program = spec.program.clone();
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => log::warn!("{program} exited with {status}"),
+        Ok(_) => {}
+        Err(err) => log::error!("waiting for {program}: {err:#}"),
+    });
+    Ok(())
+}
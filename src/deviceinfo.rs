@@ -1,13 +1,186 @@
 use anyhow::{bail, Context, Result};
+use evdev_rs::enums::{EventCode, EV_ABS, EV_KEY, EV_LED, EV_MSC, EV_REL, EV_SW};
 use evdev_rs::{Device, DeviceWrapper};
+use regex::Regex;
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The output devices created by evremap have names that start
+/// with this, so that they can be told apart from other devices
+pub const VIRTUAL_DEVICE_NAME_PREFIX: &str = "evremap Virtual input for ";
 
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
     pub name: String,
     pub path: PathBuf,
     pub phys: String,
+    pub uniq: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bustype: u16,
+    pub version: u16,
+    /// The key, relative, absolute, switch, LED and misc events
+    /// that the device reports
+    pub capabilities: HashSet<EventCode>,
+}
+
+/// Criteria that select an input device.  A device matches when
+/// it satisfies every criterion that is set.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceMatch {
+    pub name: Option<String>,
+    /// A shell style pattern, as understood by `fnmatch(3)`,
+    /// that the name must match
+    pub name_glob: Option<String>,
+    pub name_regex: Option<Regex>,
+    pub phys: Option<String>,
+    pub uniq: Option<String>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub bustype: Option<u16>,
+    pub version: Option<u16>,
+    /// Events that the device must report
+    pub required_events: Vec<EventCode>,
+    /// Events that the device must not report
+    pub forbidden_events: Vec<EventCode>,
+}
+
+/// A single criterion of a `DeviceMatch`
+enum Criterion<'a> {
+    Name(&'a str),
+    NameGlob(&'a str),
+    NameRegex(&'a Regex),
+    Phys(&'a str),
+    Uniq(&'a str),
+    VendorId(u16),
+    ProductId(u16),
+    Bustype(u16),
+    Version(u16),
+    RequiredEvents(&'a [EventCode]),
+    ForbiddenEvents(&'a [EventCode]),
+}
+
+impl Criterion<'_> {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        match self {
+            Self::Name(name) => info.name == *name,
+            Self::NameGlob(pattern) => glob_matches(pattern, &info.name),
+            Self::NameRegex(regex) => regex.is_match(&info.name),
+            Self::Phys(phys) => info.phys == *phys,
+            Self::Uniq(uniq) => info.uniq == *uniq,
+            Self::VendorId(id) => info.vendor_id == *id,
+            Self::ProductId(id) => info.product_id == *id,
+            Self::Bustype(bustype) => info.bustype == *bustype,
+            Self::Version(version) => info.version == *version,
+            Self::RequiredEvents(codes) => {
+                codes.iter().all(|code| info.capabilities.contains(code))
+            }
+            Self::ForbiddenEvents(codes) => {
+                !codes.iter().any(|code| info.capabilities.contains(code))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Criterion<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "name `{name}`"),
+            Self::NameGlob(pattern) => write!(f, "name_glob `{pattern}`"),
+            Self::NameRegex(regex) => write!(f, "name_regex `{regex}`"),
+            Self::Phys(phys) => write!(f, "phys `{phys}`"),
+            Self::Uniq(uniq) => write!(f, "uniq `{uniq}`"),
+            Self::VendorId(id) => write!(f, "vendor_id {id:#06x}"),
+            Self::ProductId(id) => write!(f, "product_id {id:#06x}"),
+            Self::Bustype(bustype) => write!(f, "bustype {bustype:#06x}"),
+            Self::Version(version) => write!(f, "version {version:#06x}"),
+            Self::RequiredEvents(codes) => write!(f, "required_events {}", join_codes(codes)),
+            Self::ForbiddenEvents(codes) => write!(f, "forbidden_events {}", join_codes(codes)),
+        }
+    }
+}
+
+fn join_codes(codes: &[EventCode]) -> String {
+    let names: Vec<String> = codes.iter().map(|code| code.to_string()).collect();
+    format!("[{}]", names.join(", "))
+}
+
+extern "C" {
+    // POSIX, but not exposed by the libc crate
+    fn fnmatch(
+        pattern: *const libc::c_char,
+        string: *const libc::c_char,
+        flags: libc::c_int,
+    ) -> libc::c_int;
+}
+
+/// Returns true if `name` matches the shell style `pattern`
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let (Ok(pattern), Ok(name)) = (CString::new(pattern), CString::new(name)) else {
+        return false;
+    };
+    unsafe { fnmatch(pattern.as_ptr(), name.as_ptr(), 0) == 0 }
+}
+
+impl DeviceMatch {
+    /// Matches devices by name, or by phys when it is given
+    pub fn with_name(name: &str, phys: Option<&str>) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            phys: phys.map(str::to_string),
+            ..Self::default()
+        }
+    }
+
+    fn criteria(&self) -> Vec<Criterion<'_>> {
+        let mut criteria = vec![];
+        // phys identifies a device on its own, and has always taken
+        // precedence over the name, which may be shared by several
+        if self.phys.is_none() {
+            criteria.extend(self.name.as_deref().map(Criterion::Name));
+        }
+        criteria.extend(self.name_glob.as_deref().map(Criterion::NameGlob));
+        criteria.extend(self.name_regex.as_ref().map(Criterion::NameRegex));
+        criteria.extend(self.phys.as_deref().map(Criterion::Phys));
+        criteria.extend(self.uniq.as_deref().map(Criterion::Uniq));
+        criteria.extend(self.vendor_id.map(Criterion::VendorId));
+        criteria.extend(self.product_id.map(Criterion::ProductId));
+        criteria.extend(self.bustype.map(Criterion::Bustype));
+        criteria.extend(self.version.map(Criterion::Version));
+        if !self.required_events.is_empty() {
+            criteria.push(Criterion::RequiredEvents(&self.required_events));
+        }
+        if !self.forbidden_events.is_empty() {
+            criteria.push(Criterion::ForbiddenEvents(&self.forbidden_events));
+        }
+        criteria
+    }
+
+    /// Returns true if no criteria are set
+    pub fn is_empty(&self) -> bool {
+        self.criteria().is_empty()
+    }
+
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        self.criteria()
+            .iter()
+            .all(|criterion| criterion.matches(info))
+    }
+}
+
+impl fmt::Display for DeviceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, criterion) in self.criteria().iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{criterion}")?;
+        }
+        Ok(())
+    }
 }
 
 impl DeviceInfo {
@@ -19,53 +192,76 @@ impl DeviceInfo {
         Ok(Self {
             name: input.name().unwrap_or("").to_string(),
             phys: input.phys().unwrap_or("").to_string(),
+            uniq: input.uniq().unwrap_or("").to_string(),
+            vendor_id: input.vendor_id(),
+            product_id: input.product_id(),
+            bustype: input.bustype(),
+            version: input.version(),
+            capabilities: capabilities(&input),
             path,
         })
     }
 
-    pub fn with_name(name: &str, phys: Option<&str>) -> Result<Self> {
-        let mut devices = Self::obtain_device_list()?;
-
-        if let Some(phys) = phys {
-            match devices.iter().position(|item| item.phys == phys) {
-                Some(idx) => return Ok(devices.remove(idx)),
-                None => {
-                    bail!(
-                        "Requested device `{}` with phys=`{}` was not found",
-                        name,
-                        phys
-                    );
-                }
-            }
-        }
-
-        let mut devices_with_name: Vec<_> = devices
-            .into_iter()
-            .filter(|item| item.name == name)
-            .collect();
-
-        if devices_with_name.is_empty() {
-            bail!("No device found with name `{}`", name);
-        }
+    /// Returns the first device that matches `device_match`
+    pub fn with_match(device_match: &DeviceMatch) -> Result<Self> {
+        let mut matching_devices = Self::all_matching(device_match, log::Level::Error)?;
 
-        if devices_with_name.len() > 1 {
-            log::warn!("The following devices match name `{}`:", name);
-            for dev in &devices_with_name {
-                log::warn!("{:?}", dev);
+        if matching_devices.len() > 1 {
+            log::warn!("The following devices match {}:", device_match);
+            for dev in &matching_devices {
+                log::warn!("{} `{}` phys=`{}`", dev.path.display(), dev.name, dev.phys);
             }
             log::warn!(
                 "evremap will use the first entry. If you want to \
                        use one of the others, add the corresponding phys \
                        value to your configuration, for example, \
                        `phys = \"{}\"` for the second entry in the list.",
-                devices_with_name[1].phys
+                matching_devices[1].phys
             );
         }
 
-        Ok(devices_with_name.remove(0))
+        let device = matching_devices.remove(0);
+        device.log_match(device_match);
+        Ok(device)
     }
 
-    fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
+    /// Returns every device that matches `device_match`.  It is
+    /// an error for there to be no such device.  Devices that can't
+    /// be opened are logged at `open_failures` level.
+    pub fn all_matching(
+        device_match: &DeviceMatch,
+        open_failures: log::Level,
+    ) -> Result<Vec<Self>> {
+        if device_match.is_empty() {
+            bail!("No criteria were given to select the device");
+        }
+
+        let matching_devices: Vec<_> = Self::obtain_device_list(open_failures)?
+            .into_iter()
+            .filter(|item| device_match.matches(item))
+            .collect();
+
+        if matching_devices.is_empty() {
+            bail!("No device found matching {}", device_match);
+        }
+        Ok(matching_devices)
+    }
+
+    /// Returns true if this is an output device created by evremap
+    pub fn is_evremap_output(&self) -> bool {
+        self.name.starts_with(VIRTUAL_DEVICE_NAME_PREFIX)
+    }
+
+    pub fn log_match(&self, device_match: &DeviceMatch) {
+        log::info!(
+            "Using {} `{}`, matched by {}",
+            self.path.display(),
+            self.name,
+            device_match
+        );
+    }
+
+    fn obtain_device_list(open_failures: log::Level) -> Result<Vec<DeviceInfo>> {
         let mut devices = vec![];
         for entry in std::fs::read_dir("/dev/input")? {
             let entry = entry?;
@@ -85,7 +281,7 @@ impl DeviceInfo {
 
             match DeviceInfo::with_path(path) {
                 Ok(item) => devices.push(item),
-                Err(err) => log::error!("{:#}", err),
+                Err(err) => log::log!(open_failures, "{:#}", err),
             }
         }
 
@@ -101,7 +297,21 @@ impl DeviceInfo {
     }
 }
 
-fn event_number_from_path(path: &PathBuf) -> u32 {
+/// Returns the events that `input` reports, of the types that
+/// `DeviceMatch` can select on
+fn capabilities(input: &Device) -> HashSet<EventCode> {
+    EventCode::EV_KEY(EV_KEY::KEY_RESERVED)
+        .iter()
+        .chain(EventCode::EV_REL(EV_REL::REL_X).iter())
+        .chain(EventCode::EV_ABS(EV_ABS::ABS_X).iter())
+        .chain(EventCode::EV_SW(EV_SW::SW_LID).iter())
+        .chain(EventCode::EV_LED(EV_LED::LED_NUML).iter())
+        .chain(EventCode::EV_MSC(EV_MSC::MSC_SERIAL).iter())
+        .filter(|code| input.has(*code))
+        .collect()
+}
+
+fn event_number_from_path(path: &Path) -> u32 {
     match path.to_str() {
         Some(s) => match s.rfind("event") {
             Some(idx) => s[idx + 5..].parse().unwrap_or(0),
@@ -112,11 +322,16 @@ fn event_number_from_path(path: &PathBuf) -> u32 {
 }
 
 pub fn list_devices() -> Result<()> {
-    let devices = DeviceInfo::obtain_device_list()?;
+    let devices = DeviceInfo::obtain_device_list(log::Level::Error)?;
     for item in &devices {
         println!("Name: {}", item.name);
         println!("Path: {}", item.path.display());
         println!("Phys: {}", item.phys);
+        println!("Uniq: {}", item.uniq);
+        println!(
+            "ID: vendor_id={:#06x} product_id={:#06x} bustype={:#06x} version={:#06x}",
+            item.vendor_id, item.product_id, item.bustype, item.version
+        );
         println!();
     }
     Ok(())
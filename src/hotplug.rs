@@ -0,0 +1,403 @@
+use crate::deviceinfo::{DeviceInfo, DeviceMatch};
+use crate::mapping::DeviceConfig;
+use crate::remapper::{wait_for_input, InputMapper};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+/// Watches `/dev/input` for event devices being attached.
+/// Devices that are removed are noticed by their mapper instead,
+/// when reading from them fails with ENODEV.
+struct DeviceWatcher {
+    inotify: File,
+}
+
+impl DeviceWatcher {
+    fn new() -> Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("creating inotify instance");
+        }
+        let inotify = unsafe { File::from_raw_fd(fd) };
+
+        let dir = CString::new("/dev/input")?;
+        // udev may only make a device accessible after it has
+        // been created, so attribute changes are watched too
+        let mask = libc::IN_CREATE | libc::IN_ATTRIB;
+        if unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("watching /dev/input");
+        }
+        Ok(Self { inotify })
+    }
+
+    /// Reads the pending notifications.  Returns true if any of
+    /// them concern an event device.
+    fn read_changes(&mut self) -> Result<bool> {
+        const HEADER_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = [0u8; 4096];
+        let mut changed = false;
+        loop {
+            let len = match self.inotify.read(&mut buf) {
+                Ok(0) => return Ok(changed),
+                Ok(len) => len,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(changed),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err).context("reading from inotify"),
+            };
+
+            let mut offset = 0;
+            while offset + HEADER_LEN <= len {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+                let name_start = offset + HEADER_LEN;
+                let name_end = (name_start + event.len as usize).min(len);
+                // When the queue overflows, we can't know what was missed
+                if event.mask & libc::IN_Q_OVERFLOW != 0
+                    || buf[name_start..name_end].starts_with(b"event")
+                {
+                    changed = true;
+                }
+                offset = name_end;
+            }
+        }
+    }
+}
+
+/// How `DeviceManager::attach_devices` treats configured devices
+/// that can't be found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MissingDevices {
+    /// It is an error for a device to be missing, or for it
+    /// to fail to be grabbed
+    Fail,
+    /// Missing devices are reported, and are grabbed once
+    /// they are attached
+    Wait,
+    /// Missing devices are expected, and are grabbed once
+    /// they are attached
+    Ignore,
+}
+
+/// A mapper and the devices that it has grabbed
+struct AttachedMapper {
+    /// The index of the `DeviceConfig` that the mapper was created from
+    section: usize,
+    path: PathBuf,
+    /// The paths of the merged devices, in the same order as
+    /// `DeviceConfig::merge`, or `None` for those that weren't
+    /// attached when the mapper was created
+    merged: Vec<Option<PathBuf>>,
+    mapper: InputMapper,
+}
+
+impl AttachedMapper {
+    /// Returns the paths of the grabbed devices, in the order in
+    /// which the mapper indexes them
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.path).chain(self.merged.iter().flatten())
+    }
+}
+
+/// Grabs the devices selected by the config file, including those
+/// that are attached after evremap has started, and runs their mappers
+pub struct DeviceManager {
+    devices: Vec<DeviceConfig>,
+    attached: Vec<AttachedMapper>,
+    /// Devices that couldn't be grabbed.  They are retried whenever
+    /// devices change, but the failure is only reported once.
+    failed: HashSet<PathBuf>,
+    watcher: DeviceWatcher,
+}
+
+impl DeviceManager {
+    /// Grabs the configured devices.  Unless `wait_for_device`
+    /// is set, it is an error for any of them to be missing.
+    pub fn new(devices: Vec<DeviceConfig>, wait_for_device: bool) -> Result<Self> {
+        // The watch is set up first, so that devices that are attached
+        // while the others are being grabbed are not missed
+        let watcher = DeviceWatcher::new()?;
+        let mut manager = Self {
+            devices,
+            attached: vec![],
+            failed: HashSet::new(),
+            watcher,
+        };
+        manager.attach_devices(if wait_for_device {
+            MissingDevices::Wait
+        } else {
+            MissingDevices::Fail
+        })?;
+        Ok(manager)
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        log::info!("Going into read loop");
+        loop {
+            let timeout = self
+                .attached
+                .iter()
+                .filter_map(|attached| attached.mapper.next_timeout())
+                .min();
+            let mut files = vec![&self.watcher.inotify];
+            for attached in &self.attached {
+                files.extend(attached.mapper.files());
+            }
+            let mut readable = wait_for_input(&files, timeout)?.into_iter();
+
+            let mut rescan = readable.next().unwrap_or(false) && self.watcher.read_changes()?;
+
+            let mut removed = vec![];
+            for (pos, attached) in self.attached.iter_mut().enumerate() {
+                let inputs: Vec<bool> = readable
+                    .by_ref()
+                    .take(attached.mapper.files().len())
+                    .collect();
+                for (idx, is_readable) in inputs.into_iter().enumerate() {
+                    if is_readable && !attached.mapper.read_pending_events(idx)? {
+                        let path = attached.paths().nth(idx).cloned().unwrap_or_default();
+                        log::warn!("{} was removed", path.display());
+                        removed.push(pos);
+                        break;
+                    }
+                }
+            }
+            // Dropping the mapper releases the other devices that it
+            // grabbed, and destroys its output device, which releases
+            // any keys that it was holding down
+            for pos in removed.into_iter().rev() {
+                self.attached.remove(pos);
+                // A section may be able to continue with the devices
+                // that are still attached
+                rescan = true;
+            }
+
+            for attached in &mut self.attached {
+                attached.mapper.handle_timeouts()?;
+            }
+
+            if rescan {
+                self.attach_devices(MissingDevices::Ignore)?;
+            }
+        }
+    }
+
+    /// Grabs the configured devices that aren't already grabbed
+    fn attach_devices(&mut self, missing: MissingDevices) -> Result<()> {
+        // A device that is attached again may have been fixed, so its
+        // failure would be worth reporting again
+        self.failed.retain(|path| path.exists());
+
+        let mut claimed: HashSet<PathBuf> = self
+            .attached
+            .iter()
+            .flat_map(|attached| attached.paths().cloned())
+            .collect();
+
+        // Sections that select a specific device take priority
+        // over `match_all` sections
+        for section in 0..self.devices.len() {
+            if !self.devices[section].match_all {
+                self.attach_section(section, &mut claimed, missing)?;
+            }
+        }
+        for section in 0..self.devices.len() {
+            if self.devices[section].match_all {
+                self.attach_all_matching(section, &mut claimed, missing)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Grabs the device selected by a section, along with the devices
+    /// that it merges.  If the section was already grabbed without
+    /// some of its merged devices, and those have since been attached,
+    /// the section is grabbed again so that they are included.
+    fn attach_section(
+        &mut self,
+        section: usize,
+        claimed: &mut HashSet<PathBuf>,
+        missing: MissingDevices,
+    ) -> Result<()> {
+        let existing = self
+            .attached
+            .iter()
+            .position(|attached| attached.section == section);
+        let device = &self.devices[section];
+
+        let path = match existing {
+            Some(pos) => self.attached[pos].path.clone(),
+            None => match find_unclaimed(&device.device_match, claimed, missing)? {
+                Some(path) => path,
+                None => return Ok(()),
+            },
+        };
+        let mut merged = vec![];
+        for (idx, merge) in device.merge.iter().enumerate() {
+            let merged_path = match existing.and_then(|pos| self.attached[pos].merged[idx].clone())
+            {
+                Some(merged_path) => Some(merged_path),
+                None => find_unclaimed(merge, claimed, missing)?,
+            };
+            merged.push(merged_path);
+        }
+
+        if let Some(pos) = existing {
+            if merged == self.attached[pos].merged {
+                return Ok(());
+            }
+            log::info!(
+                "Grabbing {} again to include newly attached devices",
+                path.display()
+            );
+            self.attached.remove(pos);
+        }
+
+        let merged_paths: Vec<PathBuf> = merged.iter().flatten().cloned().collect();
+        let mapper = match InputMapper::create_mapper(path.clone(), &merged_paths, device) {
+            Ok(mapper) => mapper,
+            Err(err) => return report_failure(err, &path, &mut self.failed, missing),
+        };
+
+        self.failed.remove(&path);
+        claimed.insert(path.clone());
+        claimed.extend(merged_paths);
+        self.attached.push(AttachedMapper {
+            section,
+            path,
+            merged,
+            mapper,
+        });
+        Ok(())
+    }
+
+    /// Grabs each device that a `match_all` section selects,
+    /// other than those that have already been claimed
+    fn attach_all_matching(
+        &mut self,
+        section: usize,
+        claimed: &mut HashSet<PathBuf>,
+        missing: MissingDevices,
+    ) -> Result<()> {
+        let device = &self.devices[section];
+        let infos = match remappable_matching(&device.device_match, missing) {
+            Ok(infos) => infos,
+            Err(err) => return report_missing(err, missing),
+        };
+
+        for info in infos {
+            if claimed.contains(&info.path) {
+                continue;
+            }
+            info.log_match(&device.device_match);
+            let mapper = match InputMapper::create_mapper(info.path.clone(), &[], device) {
+                Ok(mapper) => mapper,
+                Err(err) => {
+                    report_failure(err, &info.path, &mut self.failed, missing)?;
+                    continue;
+                }
+            };
+
+            self.failed.remove(&info.path);
+            claimed.insert(info.path.clone());
+            self.attached.push(AttachedMapper {
+                section,
+                path: info.path,
+                merged: vec![],
+                mapper,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the first device that matches `device_match`
+/// and that hasn't been claimed, or `None` if there is no such device
+fn find_unclaimed(
+    device_match: &DeviceMatch,
+    claimed: &HashSet<PathBuf>,
+    missing: MissingDevices,
+) -> Result<Option<PathBuf>> {
+    let infos = match remappable_matching(device_match, missing) {
+        Ok(infos) => infos,
+        Err(err) => {
+            report_missing(err, missing)?;
+            return Ok(None);
+        }
+    };
+    match infos.into_iter().find(|info| !claimed.contains(&info.path)) {
+        Some(info) => {
+            info.log_match(device_match);
+            Ok(Some(info.path))
+        }
+        None => {
+            report_missing(
+                anyhow::anyhow!("Every device matching {device_match} is already in use"),
+                missing,
+            )?;
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the devices that match `device_match`, other than the
+/// output devices of evremap, which must not be remapped again.
+/// It is an error for there to be no such device.
+fn remappable_matching(
+    device_match: &DeviceMatch,
+    missing: MissingDevices,
+) -> Result<Vec<DeviceInfo>> {
+    // Devices that can't be opened were already reported by the
+    // initial scan, and rescans happen whenever anything changes
+    let open_failures = match missing {
+        MissingDevices::Fail | MissingDevices::Wait => log::Level::Error,
+        MissingDevices::Ignore => log::Level::Debug,
+    };
+    let infos: Vec<DeviceInfo> = DeviceInfo::all_matching(device_match, open_failures)?
+        .into_iter()
+        .filter(|info| !info.is_evremap_output())
+        .collect();
+    if infos.is_empty() {
+        anyhow::bail!("No device found matching {device_match}");
+    }
+    Ok(infos)
+}
+
+fn report_missing(err: anyhow::Error, missing: MissingDevices) -> Result<()> {
+    match missing {
+        MissingDevices::Fail => Err(err),
+        MissingDevices::Wait => {
+            log::warn!("{err:#}. Will wait until it is attached.");
+            Ok(())
+        }
+        MissingDevices::Ignore => {
+            log::debug!("{err:#}");
+            Ok(())
+        }
+    }
+}
+
+/// Reports a device that was found, but couldn't be grabbed.
+/// Repeated failures of the same device are only logged at debug
+/// level, as the device is retried whenever devices change.
+fn report_failure(
+    err: anyhow::Error,
+    path: &Path,
+    failed: &mut HashSet<PathBuf>,
+    missing: MissingDevices,
+) -> Result<()> {
+    match missing {
+        MissingDevices::Fail => Err(err),
+        MissingDevices::Wait | MissingDevices::Ignore => {
+            if failed.insert(path.to_path_buf()) {
+                log::error!("{err:#}");
+            } else {
+                log::debug!("{err:#}");
+            }
+            Ok(())
+        }
+    }
+}
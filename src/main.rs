@@ -1,14 +1,17 @@
-use crate::deviceinfo::DeviceInfo;
+use crate::deviceinfo::{DeviceInfo, DeviceMatch};
+use crate::hotplug::DeviceManager;
 use crate::mapping::*;
-use crate::remapper::*;
 use anyhow::{Context, Result};
 use clap::Parser;
 use std::path::PathBuf;
 use std::time::Duration;
 
+mod command;
 mod deviceinfo;
+mod hotplug;
 mod mapping;
 mod remapper;
+mod text;
 
 /// Remap libinput evdev keyboard inputs
 #[derive(Debug, Parser)]
@@ -56,10 +59,9 @@ enum Opt {
         phys: Option<String>,
 
         /// If the device isn't found on startup, wait forever
-        /// until the device is plugged in. This works by polling
-        /// the set of devices every few seconds. It is not as
-        /// efficient as setting up a udev rule to spawn evremap,
-        /// but is simpler to setup ad-hoc.
+        /// until the device is plugged in, rather than exiting.
+        /// Devices that are unplugged while evremap is running are
+        /// grabbed again when they are plugged back in either way.
         #[arg(long)]
         wait_for_device: bool,
     },
@@ -90,36 +92,6 @@ fn setup_logger() {
     builder.init();
 }
 
-fn get_device(
-    device_name: &str,
-    phys: Option<&str>,
-    wait_for_device: bool,
-) -> anyhow::Result<DeviceInfo> {
-    match deviceinfo::DeviceInfo::with_name(device_name, phys) {
-        Ok(dev) => return Ok(dev),
-        Err(err) if !wait_for_device => return Err(err),
-        Err(err) => {
-            log::warn!("{err:#}. Will wait until it is attached.");
-        }
-    }
-
-    const MAX_SLEEP: Duration = Duration::from_secs(10);
-    const ONE_SECOND: Duration = Duration::from_secs(1);
-    let mut sleep = ONE_SECOND;
-
-    loop {
-        std::thread::sleep(sleep);
-        sleep = (sleep + ONE_SECOND).min(MAX_SLEEP);
-
-        match deviceinfo::DeviceInfo::with_name(device_name, phys) {
-            Ok(dev) => return Ok(dev),
-            Err(err) => {
-                log::debug!("{err:#}");
-            }
-        }
-    }
-}
-
 fn debug_events(device: DeviceInfo) -> Result<()> {
     let f =
         std::fs::File::open(&device.path).context(format!("opening {}", device.path.display()))?;
@@ -152,7 +124,8 @@ fn main() -> Result<()> {
         Opt::ListDevices => deviceinfo::list_devices(),
         Opt::ListKeys => list_keys(),
         Opt::DebugEvents { device_name, phys } => {
-            let device_info = get_device(&device_name, phys.as_deref(), false)?;
+            let device_match = DeviceMatch::with_name(&device_name, phys.as_deref());
+            let device_info = DeviceInfo::with_match(&device_match)?;
             debug_events(device_info)
         }
         Opt::Remap {
@@ -167,29 +140,38 @@ fn main() -> Result<()> {
                 config_file.display()
             ))?;
 
-            if let Some(device) = device_name {
-                mapping_config.device_name = Some(device);
-            }
-            if let Some(phys) = phys {
-                mapping_config.phys = Some(phys);
+            if device_name.is_some() || phys.is_some() {
+                let [device] = mapping_config.devices.as_mut_slice() else {
+                    anyhow::bail!(
+                        "--device-name and --phys can only be used with \
+                         a config file that describes a single device"
+                    );
+                };
+                if let Some(device_name) = device_name {
+                    device.device_match.name = Some(device_name);
+                }
+                if let Some(phys) = phys {
+                    device.device_match.phys = Some(phys);
+                }
             }
 
-            let device_name = mapping_config.device_name.as_deref().ok_or_else(|| {
-                anyhow::anyhow!(
+            if mapping_config
+                .devices
+                .iter()
+                .any(|device| device.device_match.is_empty())
+            {
+                anyhow::bail!(
                     "device_name is missing; \
-                        specify it either in the config file or via the --device-name \
-                        command line option"
-                )
-            })?;
+                        specify it, or other device criteria, in the config file \
+                        or via the --device-name command line option"
+                );
+            }
 
             log::warn!("Short delay: release any keys now!");
             std::thread::sleep(Duration::from_secs_f64(delay));
 
-            let device_info =
-                get_device(device_name, mapping_config.phys.as_deref(), wait_for_device)?;
-
-            let mut mapper = InputMapper::create_mapper(device_info.path, mapping_config.mappings)?;
-            mapper.run_mapper()
+            let mut manager = DeviceManager::new(mapping_config.devices, wait_for_device)?;
+            manager.run()
         }
     }
 }
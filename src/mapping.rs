@@ -1,15 +1,81 @@
+use crate::deviceinfo::DeviceMatch;
+use crate::text::{LayoutName, TextLayout, UnicodeInput};
 use anyhow::Context;
-pub use evdev_rs::enums::{EventCode, EventType, EV_KEY as KeyCode};
-use serde::Deserialize;
-use std::collections::HashSet;
+pub use evdev_rs::enums::{EventCode, EventType, EV_ABS, EV_KEY as KeyCode, EV_REL};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::Path;
+use std::time::Duration;
 use thiserror::Error;
 
+/// How long a dual role key may be held and still count as a tap,
+/// when neither the mapping nor the config file specify otherwise
+pub const DEFAULT_TAPPING_TERM: Duration = Duration::from_millis(200);
+
+/// How long a leader key waits for each key of a sequence,
+/// when the config file doesn't specify otherwise
+pub const DEFAULT_LEADER_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How soon after the first key of a combo the remaining keys must be
+/// pressed, when neither the combo nor the config file specify otherwise
+pub const DEFAULT_COMBO_TERM: Duration = Duration::from_millis(50);
+
+/// How soon after each key of a sequence the next key must be pressed,
+/// when neither the sequence nor the config file specify otherwise
+pub const DEFAULT_SEQUENCE_TERM: Duration = Duration::from_millis(200);
+
+/// The auto-repeat timing used when the config file doesn't specify
+/// otherwise.  These match the defaults of the kernel.
+pub const DEFAULT_REPEAT: RepeatSettings = RepeatSettings {
+    delay: Duration::from_millis(250),
+    interval: Duration::from_millis(33),
+};
+
+/// The mouse key behavior used when the config file doesn't specify
+/// otherwise.  Speeds are in pixels per second.
+pub const DEFAULT_MOUSE_KEYS: MouseKeySettings = MouseKeySettings {
+    interval: Duration::from_millis(16),
+    initial_speed: 100.0,
+    max_speed: 1200.0,
+    acceleration_time: Duration::from_millis(1000),
+    curve: AccelerationCurve::Quadratic,
+};
+
+/// How far the pointer must move to scroll by one notch while a
+/// `Mapping::ButtonScroll` button is held, when the config file
+/// doesn't specify otherwise
+pub const DEFAULT_SCROLL_DISTANCE: u32 = 10;
+
+/// How far the pointer must move for a `Mapping::Gesture` to apply,
+/// when the config file doesn't specify otherwise
+pub const DEFAULT_GESTURE_THRESHOLD: u32 = 100;
+
+/// The remapping that applies to a single input device
 #[derive(Debug, Clone)]
-pub struct MappingConfig {
-    pub device_name: Option<String>,
-    pub phys: Option<String>,
+pub struct DeviceConfig {
+    pub device_match: DeviceMatch,
+    /// When true, every device that matches `device_match` is
+    /// remapped, each with its own copy of the mappings
+    pub match_all: bool,
+    /// Further devices whose events are remapped together with
+    /// those of this device, as though they were a single device
+    pub merge: Vec<DeviceMatch>,
     pub mappings: Vec<Mapping>,
+    /// The mappings that make up each named layer
+    pub layers: HashMap<String, Vec<Mapping>>,
+    pub repeat: AutoRepeat,
+    pub mouse_keys: MouseKeySettings,
+}
+
+#[derive(Debug, Clone)]
+pub struct MappingConfig {
+    /// The devices to be remapped.  A config file without any
+    /// `[[device]]` sections describes a single device using its
+    /// top level keys.
+    pub devices: Vec<DeviceConfig>,
 }
 
 impl MappingConfig {
@@ -19,32 +85,435 @@ impl MappingConfig {
             .context(format!("reading toml from {}", path.display()))?;
         let config_file: ConfigFile =
             toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
-        let mut mappings = vec![];
-        for dual in config_file.dual_role {
-            mappings.push(dual.into());
+        Self::from_config_file(config_file)
+    }
+
+    fn from_config_file(config_file: ConfigFile) -> anyhow::Result<Self> {
+        let top_level = config_file.top_level.into_device_config()?;
+        if config_file.device.is_empty() {
+            return Ok(Self {
+                devices: vec![top_level],
+            });
         }
-        for remap in config_file.remap {
-            mappings.push(remap.into());
+
+        let mut devices = vec![];
+        if !top_level.device_match.is_empty() {
+            devices.push(top_level);
+        } else if !top_level.mappings.is_empty() || !top_level.layers.is_empty() {
+            return Err(ConfigError::MappingsWithoutDevice.into());
         }
-        Ok(Self {
-            device_name: config_file.device_name,
-            phys: config_file.phys,
-            mappings,
-        })
+        for (idx, device) in config_file.device.into_iter().enumerate() {
+            devices.push(
+                device
+                    .into_device_config()
+                    .with_context(|| format!("in [[device]] entry {}", idx + 1))?,
+            );
+        }
+        Ok(Self { devices })
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+impl std::str::FromStr for MappingConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(toml_data: &str) -> anyhow::Result<Self> {
+        let config_file: ConfigFile = toml::from_str(toml_data).context("parsing toml")?;
+        Self::from_config_file(config_file)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Mapping {
     DualRole {
         input: KeyCode,
-        hold: Vec<KeyCode>,
+        hold: Action,
         tap: Vec<KeyCode>,
+        /// If released within this duration, the key is a tap
+        tapping_term: Duration,
+        /// When true, the key produces nothing until it is either
+        /// released (a tap) or held for longer than `tapping_term`
+        /// (a hold), rather than producing `hold` immediately
+        hold_on_timeout: bool,
+        /// How other keys pressed while a `hold_on_timeout` key is
+        /// undecided influence the decision
+        interrupt: InterruptPolicy,
     },
     Remap {
         input: HashSet<KeyCode>,
         output: HashSet<KeyCode>,
+        /// How the output repeats while held, or `None` if it
+        /// doesn't repeat
+        repeat: Option<RepeatSettings>,
+    },
+    /// Behaves like a regular modifier while held, but when tapped,
+    /// applies `output` to the next key that is pressed
+    OneShot {
+        input: KeyCode,
+        output: Vec<KeyCode>,
+        /// If released within this duration, the key is a tap
+        tapping_term: Duration,
+        /// If no key is pressed within this duration of a tap,
+        /// the modifier is dropped
+        timeout: Option<Duration>,
+    },
+    /// Produces a different result depending on how many times
+    /// the key is tapped in quick succession
+    TapDance {
+        input: KeyCode,
+        /// The first entry applies to a single tap, the second to
+        /// a double tap and so on
+        steps: Vec<TapDanceStep>,
+        /// The maximum duration of each tap, and of the gap
+        /// between successive taps
+        tapping_term: Duration,
+    },
+    /// Performs `action` when the chord of `input` keys is pressed,
+    /// or released, depending on `on`.  The `input` keys are not
+    /// passed through to the output.
+    Trigger {
+        input: HashSet<KeyCode>,
+        action: Action,
+        on: TriggerEdge,
+    },
+    /// Activates the mappings of the named layer
+    LayerSwitch {
+        input: KeyCode,
+        layer: String,
+        mode: LayerMode,
+    },
+    /// Produces `output` while the `input` keys are held, provided
+    /// that they were all pressed within `term` of each other.
+    /// Otherwise the keys behave as though there were no combo.
+    Combo {
+        input: HashSet<KeyCode>,
+        output: Vec<KeyCode>,
+        term: Duration,
+    },
+    /// Taps `output` in place of the `input` keys when they are
+    /// pressed one after the other, each within `term` of the last.
+    /// Otherwise the keys behave as though there were no sequence.
+    Sequence {
+        input: Vec<KeyCode>,
+        output: Vec<KeyCode>,
+        term: Duration,
+    },
+    /// Taps `output` for each notch that the wheel is scrolled in
+    /// `direction` while the `held` keys are held, in place of the
+    /// wheel event
+    WheelToKey {
+        direction: WheelDirection,
+        held: HashSet<KeyCode>,
+        output: Vec<KeyCode>,
+    },
+    /// Moves the pointer in `direction` while `input` is held
+    MouseKey {
+        input: KeyCode,
+        direction: PointerDirection,
+    },
+    /// While `button` is held, pointer motion scrolls instead.
+    /// Clicking `button` without moving the pointer passes the
+    /// click through.
+    ButtonScroll {
+        button: KeyCode,
+        /// The pointer motion that scrolls by one notch
+        distance: u32,
     },
+    /// Performs `action` when `button` is released after moving the
+    /// pointer at least `threshold` in `direction` while it was held.
+    /// The pointer doesn't move while `button` is held, and clicking
+    /// it without performing a gesture passes the click through.
+    Gesture {
+        button: KeyCode,
+        direction: PointerDirection,
+        threshold: u32,
+        action: Action,
+    },
+    /// Transforms the motion of the `input` axis while the `held`
+    /// keys are held, before it reaches the output device
+    Relative {
+        input: EV_REL,
+        held: HashSet<KeyCode>,
+        /// The axis that the transformed motion is emitted on
+        output: EV_REL,
+        /// The motion is multiplied by this factor; a negative
+        /// factor inverts the axis
+        scale: f64,
+    },
+    /// Holds `output` while the `axis` is pushed past `threshold`
+    /// in `direction`
+    AbsToKey {
+        axis: EV_ABS,
+        direction: AxisDirection,
+        /// The axis value at which `output` is pressed.  When not
+        /// specified, it is halfway between the center and the end
+        /// of the axis.
+        threshold: Option<i32>,
+        output: Vec<KeyCode>,
+    },
+    /// Pressing `input` captures the keys that follow and matches
+    /// them against `sequences`.  The captured keys are replayed
+    /// if they don't match any sequence.
+    Leader {
+        input: KeyCode,
+        /// How long to wait for each key of a sequence
+        timeout: Duration,
+        sequences: Vec<LeaderSequence>,
+    },
+}
+
+impl Mapping {
+    /// Returns the names of the layers that this mapping can activate
+    pub fn referenced_layers(&self) -> Vec<&str> {
+        match self {
+            Mapping::DualRole {
+                hold: Action::Layer(layer),
+                ..
+            }
+            | Mapping::Gesture {
+                action: Action::Layer(layer),
+                ..
+            }
+            | Mapping::LayerSwitch { layer, .. } => vec![layer],
+            Mapping::TapDance { steps, .. } => steps
+                .iter()
+                .filter_map(|step| match &step.hold {
+                    Some(Action::Layer(layer)) => Some(layer.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            Mapping::Leader { sequences, .. } => sequences
+                .iter()
+                .filter_map(|seq| match &seq.action {
+                    Action::Layer(layer) => Some(layer.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TapDanceStep {
+    /// Emitted when the key is released after this many taps
+    pub tap: Vec<KeyCode>,
+    /// Applies while the key is held after this many taps.
+    /// When not specified, `tap` is held instead.
+    pub hold: Option<Action>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RepeatSettings {
+    /// How long a key must be held before it starts repeating
+    pub delay: Duration,
+    /// The time between repeats
+    pub interval: Duration,
+}
+
+/// Controls the repeating of held output keys, which evremap
+/// generates itself rather than passing on those of the input device
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AutoRepeat {
+    /// Applies to keys that aren't produced by a remap entry
+    /// with its own settings
+    pub settings: RepeatSettings,
+    /// These keys never repeat
+    pub exclude: HashSet<KeyCode>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LeaderSequence {
+    /// The keys that must be tapped, in order, after the leader key
+    pub keys: Vec<KeyCode>,
+    pub action: Action,
+}
+
+/// Something that a key can do other than produce itself
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Action {
+    /// Produce these keys
+    Keys(Vec<KeyCode>),
+    /// Activate the named layer
+    Layer(String),
+    /// Emit a sequence of key events
+    Macro(Vec<MacroStep>),
+    /// Spawn a program
+    Command(CommandSpec),
+    /// Scroll the wheel by one notch
+    Wheel(WheelDirection),
+}
+
+/// Describes a program to be spawned by `Action::Command`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Additional environment variables for the program
+    pub env: HashMap<String, String>,
+    /// Run the program as this user rather than as the user
+    /// running evremap
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MacroStep {
+    Press(KeyCode),
+    Release(KeyCode),
+    /// Press and then release a key
+    Tap(KeyCode),
+    /// Wait before moving on to the next step
+    Delay(Duration),
+}
+
+/// The direction in which a mouse wheel is scrolled
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WheelDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl WheelDirection {
+    /// Returns the direction of a wheel event, and whether it is
+    /// on one of the high resolution axes
+    pub fn from_event(axis: EV_REL, value: i32) -> Option<(Self, bool)> {
+        let (direction, hi_res) = match axis {
+            EV_REL::REL_WHEEL | EV_REL::REL_WHEEL_HI_RES => (
+                if value > 0 { Self::Up } else { Self::Down },
+                axis == EV_REL::REL_WHEEL_HI_RES,
+            ),
+            EV_REL::REL_HWHEEL | EV_REL::REL_HWHEEL_HI_RES => (
+                if value > 0 { Self::Right } else { Self::Left },
+                axis == EV_REL::REL_HWHEEL_HI_RES,
+            ),
+            _ => return None,
+        };
+        if value == 0 {
+            return None;
+        }
+        Some((direction, hi_res))
+    }
+
+    /// Returns the low and high resolution axes of this direction,
+    /// and the sign of their values
+    pub fn axes(self) -> (EV_REL, EV_REL, i32) {
+        match self {
+            Self::Up => (EV_REL::REL_WHEEL, EV_REL::REL_WHEEL_HI_RES, 1),
+            Self::Down => (EV_REL::REL_WHEEL, EV_REL::REL_WHEEL_HI_RES, -1),
+            Self::Right => (EV_REL::REL_HWHEEL, EV_REL::REL_HWHEEL_HI_RES, 1),
+            Self::Left => (EV_REL::REL_HWHEEL, EV_REL::REL_HWHEEL_HI_RES, -1),
+        }
+    }
+}
+
+/// The direction in which a `Mapping::MouseKey` moves the pointer
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PointerDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl PointerDirection {
+    /// Returns the unit of motion along the x and y axes
+    pub fn vector(self) -> (i32, i32) {
+        match self {
+            Self::Up => (0, -1),
+            Self::Down => (0, 1),
+            Self::Left => (-1, 0),
+            Self::Right => (1, 0),
+        }
+    }
+}
+
+/// The direction in which an absolute axis is pushed away from
+/// its center
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+/// How the speed of the pointer increases while mouse keys are held
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccelerationCurve {
+    /// The speed increases steadily
+    Linear,
+    /// The speed increases slowly at first, which makes
+    /// small movements easier
+    #[default]
+    Quadratic,
+}
+
+impl AccelerationCurve {
+    /// Maps the fraction of the acceleration time that has
+    /// elapsed to the fraction of the maximum speed gained
+    pub fn apply(self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => progress,
+            Self::Quadratic => progress * progress,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MouseKeySettings {
+    /// The time between pointer movements
+    pub interval: Duration,
+    /// The speed when a key is first pressed, in pixels per second
+    pub initial_speed: f64,
+    /// The speed reached after `acceleration_time`, in pixels per second
+    pub max_speed: f64,
+    pub acceleration_time: Duration,
+    pub curve: AccelerationCurve,
+}
+
+/// Selects whether a `Mapping::Trigger` fires when its chord is
+/// completed, or when the chord is subsequently released
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TriggerEdge {
+    #[default]
+    Press,
+    Release,
+}
+
+/// Controls how a `LayerSwitch` key activates its layer
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayerMode {
+    /// The layer is active while the key is held
+    #[default]
+    Momentary,
+    /// Each press of the key activates or deactivates the layer
+    Toggle,
+    /// Pressing the key activates the layer for the next key press
+    OneShot,
+}
+
+/// Controls how a `hold_on_timeout` dual role key is resolved when
+/// other keys are pressed before it has been decided
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterruptPolicy {
+    /// Pressing any other key resolves the dual role key as a hold
+    #[default]
+    HoldOnOtherKeyPress,
+    /// The dual role key is resolved as a hold if another key is
+    /// both pressed and released while it is held.  Other keys are
+    /// held back until the decision has been made.
+    PermissiveHold,
+    /// Other keys have no influence; the dual role key is a hold
+    /// only once the tapping term has elapsed.  Other keys are
+    /// held back until the decision has been made.
+    TapPreferred,
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,9 +522,9 @@ struct KeyCodeWrapper {
     pub code: KeyCode,
 }
 
-impl Into<KeyCode> for KeyCodeWrapper {
-    fn into(self) -> KeyCode {
-        self.code
+impl From<KeyCodeWrapper> for KeyCode {
+    fn from(val: KeyCodeWrapper) -> Self {
+        val.code
     }
 }
 
@@ -63,8 +532,39 @@ impl Into<KeyCode> for KeyCodeWrapper {
 pub enum ConfigError {
     #[error("Invalid key `{0}`.  Use `evremap list-keys` to see possible keys.")]
     InvalidKey(String),
+    #[error("Invalid axis `{0}`.  Expected a name such as `REL_X` or `ABS_X`.")]
+    InvalidAxis(String),
+    #[error("Invalid event `{0}`.  Expected a name such as `KEY_A`, `BTN_LEFT` or `REL_X`.")]
+    InvalidEventCode(String),
     #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
     ImpossibleParseKey,
+    #[error("tap_dance for `{0:?}` must have at least one entry in `taps`")]
+    EmptyTapDance(KeyCode),
+    #[error("Cannot type `{0}`: it isn't in the layout, and unicode input is disabled")]
+    UntypeableCharacter(char),
+    #[error("Layer `{0}` is referenced but not defined by a `[layer.{0}]` table")]
+    UnknownLayer(String),
+    #[error("`hold` for `{0:?}` must be a list of keys or a layer")]
+    InvalidHoldAction(KeyCode),
+    #[error("combo {0:?} must have at least two distinct input keys")]
+    ComboTooShort(Vec<KeyCode>),
+    #[error("sequence {0:?} must have at least two input keys")]
+    SequenceTooShort(Vec<KeyCode>),
+    #[error("leader for `{0:?}` has a sequence with no keys")]
+    EmptyLeaderSequence(KeyCode),
+    #[error(
+        "mappings at the top level of the config file need a top level \
+         `device_name`, or other device criteria, when `[[device]]` sections are used"
+    )]
+    MappingsWithoutDevice,
+    #[error("Invalid name_regex `{0}`: {1}")]
+    InvalidNameRegex(String, regex::Error),
+    #[error("`merge` entries must specify at least one device criterion, such as `device_name`")]
+    EmptyMerge,
+    #[error("`merge` can't be used together with `match_all`")]
+    MergeWithMatchAll,
+    #[error("repeat `delay_ms` and `interval_ms` must be greater than zero")]
+    ZeroRepeatTime,
 }
 
 impl std::convert::TryFrom<String> for KeyCodeWrapper {
@@ -80,20 +580,258 @@ impl std::convert::TryFrom<String> for KeyCodeWrapper {
     }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct RelAxisWrapper {
+    pub axis: EV_REL,
+}
+
+impl From<RelAxisWrapper> for EV_REL {
+    fn from(val: RelAxisWrapper) -> Self {
+        val.axis
+    }
+}
+
+impl std::convert::TryFrom<String> for RelAxisWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<RelAxisWrapper, Self::Error> {
+        match EventCode::from_str(&EventType::EV_REL, &s) {
+            Some(EventCode::EV_REL(axis)) => Ok(RelAxisWrapper { axis }),
+            _ => Err(ConfigError::InvalidAxis(s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct AbsAxisWrapper {
+    pub axis: EV_ABS,
+}
+
+impl From<AbsAxisWrapper> for EV_ABS {
+    fn from(val: AbsAxisWrapper) -> Self {
+        val.axis
+    }
+}
+
+impl std::convert::TryFrom<String> for AbsAxisWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<AbsAxisWrapper, Self::Error> {
+        match EventCode::from_str(&EventType::EV_ABS, &s) {
+            Some(EventCode::EV_ABS(axis)) => Ok(AbsAxisWrapper { axis }),
+            _ => Err(ConfigError::InvalidAxis(s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct EventCodeWrapper {
+    pub code: EventCode,
+}
+
+impl From<EventCodeWrapper> for EventCode {
+    fn from(val: EventCodeWrapper) -> Self {
+        val.code
+    }
+}
+
+impl std::convert::TryFrom<String> for EventCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<EventCodeWrapper, Self::Error> {
+        [
+            EventType::EV_KEY,
+            EventType::EV_REL,
+            EventType::EV_ABS,
+            EventType::EV_SW,
+            EventType::EV_LED,
+            EventType::EV_MSC,
+        ]
+        .iter()
+        .find_map(|event_type| EventCode::from_str(event_type, &s))
+        .map(|code| EventCodeWrapper { code })
+        .ok_or(ConfigError::InvalidEventCode(s))
+    }
+}
+
+/// An action is written either as a list of keys, or as a table
+/// with a single entry that names the kind of action, such as
+/// `{ layer = "nav" }` or `{ text = "hello" }`
+#[derive(Debug)]
+enum ActionConfig {
+    Keys(Vec<KeyCodeWrapper>),
+    Table(ActionTableConfig),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ActionTableConfig {
+    Layer(String),
+    Macro(Vec<MacroStepConfig>),
+    Command(CommandSpecConfig),
+    Text(String),
+    Wheel(WheelDirection),
+}
+
+impl<'de> Deserialize<'de> for ActionConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ActionVisitor;
+
+        impl<'de> Visitor<'de> for ActionVisitor {
+            type Value = ActionConfig;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of keys or a table such as `{ layer = \"NAME\" }`")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Deserialize::deserialize(SeqAccessDeserializer::new(seq)).map(ActionConfig::Keys)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                Deserialize::deserialize(MapAccessDeserializer::new(map)).map(ActionConfig::Table)
+            }
+        }
+
+        deserializer.deserialize_any(ActionVisitor)
+    }
+}
+
+impl ActionConfig {
+    fn into_action(self, layout: &TextLayout) -> Result<Action, ConfigError> {
+        Ok(match self {
+            ActionConfig::Keys(keys) => Action::Keys(keys.into_iter().map(Into::into).collect()),
+            ActionConfig::Table(ActionTableConfig::Layer(layer)) => Action::Layer(layer),
+            ActionConfig::Table(ActionTableConfig::Macro(steps)) => {
+                Action::Macro(steps.into_iter().map(Into::into).collect())
+            }
+            ActionConfig::Table(ActionTableConfig::Command(spec)) => Action::Command(spec.into()),
+            ActionConfig::Table(ActionTableConfig::Text(text)) => {
+                Action::Macro(layout.text_to_steps(&text)?)
+            }
+            ActionConfig::Table(ActionTableConfig::Wheel(direction)) => Action::Wheel(direction),
+        })
+    }
+
+    /// Converts an action that applies while `input` is held; only
+    /// keys and layers can be held
+    fn into_hold_action(self, input: KeyCode) -> Result<Action, ConfigError> {
+        match self {
+            ActionConfig::Keys(keys) => {
+                Ok(Action::Keys(keys.into_iter().map(Into::into).collect()))
+            }
+            ActionConfig::Table(ActionTableConfig::Layer(layer)) => Ok(Action::Layer(layer)),
+            ActionConfig::Table(_) => Err(ConfigError::InvalidHoldAction(input)),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct DualRoleConfig {
     input: KeyCodeWrapper,
-    hold: Vec<KeyCodeWrapper>,
+    hold: ActionConfig,
     tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    tapping_term_ms: Option<u64>,
+    #[serde(default)]
+    hold_on_timeout: bool,
+    /// Specifying an interrupt policy implies `hold_on_timeout`
+    #[serde(default)]
+    interrupt: Option<InterruptPolicy>,
 }
 
-impl Into<Mapping> for DualRoleConfig {
-    fn into(self) -> Mapping {
-        Mapping::DualRole {
-            input: self.input.into(),
-            hold: self.hold.into_iter().map(Into::into).collect(),
+impl DualRoleConfig {
+    fn into_mapping(self, default_tapping_term: Duration) -> Result<Mapping, ConfigError> {
+        let input = self.input.into();
+        Ok(Mapping::DualRole {
+            input,
+            hold: self.hold.into_hold_action(input)?,
             tap: self.tap.into_iter().map(Into::into).collect(),
+            tapping_term: self
+                .tapping_term_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_tapping_term),
+            hold_on_timeout: self.hold_on_timeout || self.interrupt.is_some(),
+            interrupt: self.interrupt.unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RepeatSettingsConfig {
+    #[serde(default)]
+    delay_ms: Option<u64>,
+    #[serde(default)]
+    interval_ms: Option<u64>,
+}
+
+impl RepeatSettingsConfig {
+    fn into_settings(self, defaults: &RepeatSettings) -> Result<RepeatSettings, ConfigError> {
+        if self.delay_ms == Some(0) || self.interval_ms == Some(0) {
+            return Err(ConfigError::ZeroRepeatTime);
+        }
+        Ok(RepeatSettings {
+            delay: self
+                .delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.delay),
+            interval: self
+                .interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.interval),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RepeatConfig {
+    #[serde(flatten)]
+    settings: RepeatSettingsConfig,
+    #[serde(default)]
+    exclude: Vec<KeyCodeWrapper>,
+}
+
+impl TryFrom<RepeatConfig> for AutoRepeat {
+    type Error = ConfigError;
+
+    fn try_from(val: RepeatConfig) -> Result<Self, Self::Error> {
+        Ok(AutoRepeat {
+            settings: val.settings.into_settings(&DEFAULT_REPEAT)?,
+            exclude: val.exclude.into_iter().map(Into::into).collect(),
+        })
+    }
+}
+
+/// The `repeat` of a remap entry is written either as a boolean
+/// that enables or disables repeating, or as a table of settings
+#[derive(Debug)]
+enum RemapRepeatConfig {
+    Enabled(bool),
+    Settings(RepeatSettingsConfig),
+}
+
+impl<'de> Deserialize<'de> for RemapRepeatConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RepeatVisitor;
+
+        impl<'de> Visitor<'de> for RepeatVisitor {
+            type Value = RemapRepeatConfig;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a boolean or a table such as `{ delay_ms = 500 }`")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(RemapRepeatConfig::Enabled(v))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                Deserialize::deserialize(MapAccessDeserializer::new(map))
+                    .map(RemapRepeatConfig::Settings)
+            }
         }
+
+        deserializer.deserialize_any(RepeatVisitor)
     }
 }
 
@@ -101,28 +839,801 @@ impl Into<Mapping> for DualRoleConfig {
 struct RemapConfig {
     input: Vec<KeyCodeWrapper>,
     output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    repeat: Option<RemapRepeatConfig>,
 }
 
-impl Into<Mapping> for RemapConfig {
-    fn into(self) -> Mapping {
-        Mapping::Remap {
+impl RemapConfig {
+    fn into_mapping(self, default_repeat: &RepeatSettings) -> Result<Mapping, ConfigError> {
+        Ok(Mapping::Remap {
             input: self.input.into_iter().map(Into::into).collect(),
             output: self.output.into_iter().map(Into::into).collect(),
+            repeat: match self.repeat {
+                None | Some(RemapRepeatConfig::Enabled(true)) => Some(*default_repeat),
+                Some(RemapRepeatConfig::Enabled(false)) => None,
+                Some(RemapRepeatConfig::Settings(settings)) => {
+                    Some(settings.into_settings(default_repeat)?)
+                }
+            },
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MacroStepConfig {
+    Press(KeyCodeWrapper),
+    Release(KeyCodeWrapper),
+    Tap(KeyCodeWrapper),
+    DelayMs(u64),
+}
+
+impl From<MacroStepConfig> for MacroStep {
+    fn from(val: MacroStepConfig) -> Self {
+        match val {
+            MacroStepConfig::Press(key) => MacroStep::Press(key.into()),
+            MacroStepConfig::Release(key) => MacroStep::Release(key.into()),
+            MacroStepConfig::Tap(key) => MacroStep::Tap(key.into()),
+            MacroStepConfig::DelayMs(ms) => MacroStep::Delay(Duration::from_millis(ms)),
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
-struct ConfigFile {
+struct MacroConfig {
+    input: Vec<KeyCodeWrapper>,
+    steps: Vec<MacroStepConfig>,
     #[serde(default)]
-    device_name: Option<String>,
+    on: TriggerEdge,
+}
+
+impl From<MacroConfig> for Mapping {
+    fn from(val: MacroConfig) -> Self {
+        Mapping::Trigger {
+            input: val.input.into_iter().map(Into::into).collect(),
+            action: Action::Macro(val.steps.into_iter().map(Into::into).collect()),
+            on: val.on,
+        }
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct CommandSpecConfig {
+    program: String,
     #[serde(default)]
-    phys: Option<String>,
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    user: Option<String>,
+}
+
+impl From<CommandSpecConfig> for CommandSpec {
+    fn from(val: CommandSpecConfig) -> Self {
+        CommandSpec {
+            program: val.program,
+            args: val.args,
+            env: val.env,
+            user: val.user,
+        }
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct CommandConfig {
+    input: Vec<KeyCodeWrapper>,
+    #[serde(flatten)]
+    spec: CommandSpecConfig,
     #[serde(default)]
-    dual_role: Vec<DualRoleConfig>,
+    on: TriggerEdge,
+}
+
+impl From<CommandConfig> for Mapping {
+    fn from(val: CommandConfig) -> Self {
+        Mapping::Trigger {
+            input: val.input.into_iter().map(Into::into).collect(),
+            action: Action::Command(val.spec.into()),
+            on: val.on,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TextConfig {
+    input: Vec<KeyCodeWrapper>,
+    text: String,
+    #[serde(default)]
+    on: TriggerEdge,
+}
+
+impl TextConfig {
+    fn into_mapping(self, layout: &TextLayout) -> Result<Mapping, ConfigError> {
+        Ok(Mapping::Trigger {
+            input: self.input.into_iter().map(Into::into).collect(),
+            action: Action::Macro(layout.text_to_steps(&self.text)?),
+            on: self.on,
+        })
+    }
+}
 
+#[derive(Debug, Default, Deserialize)]
+struct TextInputConfig {
     #[serde(default)]
-    remap: Vec<RemapConfig>,
+    layout: LayoutName,
+    #[serde(default)]
+    unicode: UnicodeInput,
+    #[serde(default)]
+    keys: HashMap<char, Vec<KeyCodeWrapper>>,
+}
+
+impl From<TextInputConfig> for TextLayout {
+    fn from(val: TextInputConfig) -> Self {
+        TextLayout {
+            layout: val.layout,
+            unicode: val.unicode,
+            overrides: val
+                .keys
+                .into_iter()
+                .map(|(c, keys)| (c, keys.into_iter().map(Into::into).collect()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TapDanceStepConfig {
+    tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    hold: Option<ActionConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TapDanceConfig {
+    input: KeyCodeWrapper,
+    taps: Vec<TapDanceStepConfig>,
+    #[serde(default)]
+    tapping_term_ms: Option<u64>,
+}
+
+impl TapDanceConfig {
+    fn into_mapping(self, default_tapping_term: Duration) -> Result<Mapping, ConfigError> {
+        let input = self.input.into();
+        if self.taps.is_empty() {
+            return Err(ConfigError::EmptyTapDance(input));
+        }
+        Ok(Mapping::TapDance {
+            input,
+            steps: self
+                .taps
+                .into_iter()
+                .map(|step| {
+                    Ok(TapDanceStep {
+                        tap: step.tap.into_iter().map(Into::into).collect(),
+                        hold: step
+                            .hold
+                            .map(|hold| hold.into_hold_action(input))
+                            .transpose()?,
+                    })
+                })
+                .collect::<Result<_, ConfigError>>()?,
+            tapping_term: self
+                .tapping_term_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_tapping_term),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OneShotConfig {
+    input: KeyCodeWrapper,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    tapping_term_ms: Option<u64>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+impl OneShotConfig {
+    fn into_mapping(self, default_tapping_term: Duration) -> Mapping {
+        Mapping::OneShot {
+            input: self.input.into(),
+            output: self.output.into_iter().map(Into::into).collect(),
+            tapping_term: self
+                .tapping_term_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_tapping_term),
+            timeout: self.timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LayerSwitchConfig {
+    input: KeyCodeWrapper,
+    layer: String,
+    #[serde(default)]
+    mode: LayerMode,
+}
+
+impl From<LayerSwitchConfig> for Mapping {
+    fn from(val: LayerSwitchConfig) -> Self {
+        Mapping::LayerSwitch {
+            input: val.input.into(),
+            layer: val.layer,
+            mode: val.mode,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WheelToKeyConfig {
+    direction: WheelDirection,
+    #[serde(default)]
+    held: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+}
+
+impl From<WheelToKeyConfig> for Mapping {
+    fn from(val: WheelToKeyConfig) -> Self {
+        Mapping::WheelToKey {
+            direction: val.direction,
+            held: val.held.into_iter().map(Into::into).collect(),
+            output: val.output.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyToWheelConfig {
+    input: Vec<KeyCodeWrapper>,
+    direction: WheelDirection,
+}
+
+impl From<KeyToWheelConfig> for Mapping {
+    fn from(val: KeyToWheelConfig) -> Self {
+        Mapping::Trigger {
+            input: val.input.into_iter().map(Into::into).collect(),
+            action: Action::Wheel(val.direction),
+            on: TriggerEdge::Press,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MouseKeyConfig {
+    input: KeyCodeWrapper,
+    direction: PointerDirection,
+}
+
+impl From<MouseKeyConfig> for Mapping {
+    fn from(val: MouseKeyConfig) -> Self {
+        Mapping::MouseKey {
+            input: val.input.into(),
+            direction: val.direction,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ButtonScrollConfig {
+    button: KeyCodeWrapper,
+    #[serde(default)]
+    distance: Option<u32>,
+}
+
+impl From<ButtonScrollConfig> for Mapping {
+    fn from(val: ButtonScrollConfig) -> Self {
+        Mapping::ButtonScroll {
+            button: val.button.into(),
+            distance: val.distance.unwrap_or(DEFAULT_SCROLL_DISTANCE).max(1),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GestureConfig {
+    button: KeyCodeWrapper,
+    direction: PointerDirection,
+    #[serde(default)]
+    threshold: Option<u32>,
+    action: ActionConfig,
+}
+
+impl GestureConfig {
+    fn into_mapping(self, layout: &TextLayout) -> Result<Mapping, ConfigError> {
+        Ok(Mapping::Gesture {
+            button: self.button.into(),
+            direction: self.direction,
+            threshold: self.threshold.unwrap_or(DEFAULT_GESTURE_THRESHOLD),
+            action: self.action.into_action(layout)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RelativeConfig {
+    axis: RelAxisWrapper,
+    #[serde(default)]
+    held: Vec<KeyCodeWrapper>,
+    /// Emit the motion on this axis instead
+    #[serde(default)]
+    to: Option<RelAxisWrapper>,
+    #[serde(default)]
+    scale: Option<f64>,
+    #[serde(default)]
+    invert: bool,
+}
+
+impl From<RelativeConfig> for Mapping {
+    fn from(val: RelativeConfig) -> Self {
+        let input = val.axis.into();
+        let scale = val.scale.unwrap_or(1.0);
+        Mapping::Relative {
+            input,
+            held: val.held.into_iter().map(Into::into).collect(),
+            output: val.to.map(Into::into).unwrap_or(input),
+            scale: if val.invert { -scale } else { scale },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AbsToKeyConfig {
+    axis: AbsAxisWrapper,
+    direction: AxisDirection,
+    #[serde(default)]
+    threshold: Option<i32>,
+    output: Vec<KeyCodeWrapper>,
+}
+
+impl From<AbsToKeyConfig> for Mapping {
+    fn from(val: AbsToKeyConfig) -> Self {
+        Mapping::AbsToKey {
+            axis: val.axis.into(),
+            direction: val.direction,
+            threshold: val.threshold,
+            output: val.output.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MouseKeySettingsConfig {
+    #[serde(default)]
+    interval_ms: Option<u64>,
+    #[serde(default)]
+    initial_speed: Option<f64>,
+    #[serde(default)]
+    max_speed: Option<f64>,
+    #[serde(default)]
+    acceleration_ms: Option<u64>,
+    #[serde(default)]
+    curve: Option<AccelerationCurve>,
+}
+
+impl From<MouseKeySettingsConfig> for MouseKeySettings {
+    fn from(val: MouseKeySettingsConfig) -> Self {
+        let defaults = DEFAULT_MOUSE_KEYS;
+        MouseKeySettings {
+            interval: val
+                .interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.interval),
+            initial_speed: val.initial_speed.unwrap_or(defaults.initial_speed),
+            max_speed: val.max_speed.unwrap_or(defaults.max_speed),
+            acceleration_time: val
+                .acceleration_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.acceleration_time),
+            curve: val.curve.unwrap_or(defaults.curve),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ComboConfig {
+    input: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    term_ms: Option<u64>,
+}
+
+impl ComboConfig {
+    fn into_mapping(self, default_term: Duration) -> Result<Mapping, ConfigError> {
+        let keys: Vec<KeyCode> = self.input.into_iter().map(Into::into).collect();
+        let input: HashSet<KeyCode> = keys.iter().cloned().collect();
+        if input.len() < 2 {
+            return Err(ConfigError::ComboTooShort(keys));
+        }
+        Ok(Mapping::Combo {
+            input,
+            output: self.output.into_iter().map(Into::into).collect(),
+            term: self
+                .term_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_term),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SequenceConfig {
+    input: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    term_ms: Option<u64>,
+}
+
+impl SequenceConfig {
+    fn into_mapping(self, default_term: Duration) -> Result<Mapping, ConfigError> {
+        let input: Vec<KeyCode> = self.input.into_iter().map(Into::into).collect();
+        if input.len() < 2 {
+            return Err(ConfigError::SequenceTooShort(input));
+        }
+        Ok(Mapping::Sequence {
+            input,
+            output: self.output.into_iter().map(Into::into).collect(),
+            term: self
+                .term_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default_term),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaderSequenceConfig {
+    keys: Vec<KeyCodeWrapper>,
+    action: ActionConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaderConfig {
+    input: KeyCodeWrapper,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    sequence: Vec<LeaderSequenceConfig>,
+}
+
+impl LeaderConfig {
+    fn into_mapping(self, layout: &TextLayout) -> Result<Mapping, ConfigError> {
+        let input = self.input.into();
+        let mut sequences = vec![];
+        for seq in self.sequence {
+            if seq.keys.is_empty() {
+                return Err(ConfigError::EmptyLeaderSequence(input));
+            }
+            sequences.push(LeaderSequence {
+                keys: seq.keys.into_iter().map(Into::into).collect(),
+                action: seq.action.into_action(layout)?,
+            });
+        }
+        Ok(Mapping::Leader {
+            input,
+            timeout: self
+                .timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_LEADER_TIMEOUT),
+            sequences,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LayerConfig {
+    #[serde(default, rename = "macro")]
+    macro_: Vec<MacroConfig>,
+
+    #[serde(default)]
+    command: Vec<CommandConfig>,
+
+    #[serde(default)]
+    text: Vec<TextConfig>,
+
+    #[serde(default)]
+    key_to_wheel: Vec<KeyToWheelConfig>,
+
+    #[serde(default)]
+    mouse_key: Vec<MouseKeyConfig>,
+
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+}
+
+impl LayerConfig {
+    fn into_mappings(
+        self,
+        text_layout: &TextLayout,
+        default_repeat: &RepeatSettings,
+    ) -> Result<Vec<Mapping>, ConfigError> {
+        let mut mappings: Vec<Mapping> = self.macro_.into_iter().map(Into::into).collect();
+        mappings.extend(self.command.into_iter().map(Into::into));
+        for text in self.text {
+            mappings.push(text.into_mapping(text_layout)?);
+        }
+        mappings.extend(self.key_to_wheel.into_iter().map(Into::into));
+        mappings.extend(self.mouse_key.into_iter().map(Into::into));
+        for remap in self.remap {
+            mappings.push(remap.into_mapping(default_repeat)?);
+        }
+        Ok(mappings)
+    }
+}
+
+/// The criteria that select a device
+#[derive(Debug, Deserialize)]
+struct DeviceMatchConfig {
+    #[serde(default)]
+    device_name: Option<String>,
+
+    #[serde(default)]
+    phys: Option<String>,
+
+    #[serde(default)]
+    name_glob: Option<String>,
+
+    #[serde(default)]
+    name_regex: Option<String>,
+
+    /// The unique identifier of the device, such as the
+    /// address of a bluetooth device
+    #[serde(default)]
+    uniq: Option<String>,
+
+    #[serde(default)]
+    vendor_id: Option<u16>,
+
+    #[serde(default)]
+    product_id: Option<u16>,
+
+    #[serde(default)]
+    bustype: Option<u16>,
+
+    #[serde(default)]
+    version: Option<u16>,
+
+    #[serde(default)]
+    required_events: Vec<EventCodeWrapper>,
+
+    #[serde(default)]
+    forbidden_events: Vec<EventCodeWrapper>,
+}
+
+impl DeviceMatchConfig {
+    fn into_device_match(self) -> Result<DeviceMatch, ConfigError> {
+        let name_regex = match self.name_regex {
+            Some(pattern) => Some(
+                regex::Regex::new(&pattern)
+                    .map_err(|err| ConfigError::InvalidNameRegex(pattern, err))?,
+            ),
+            None => None,
+        };
+        Ok(DeviceMatch {
+            name: self.device_name,
+            name_glob: self.name_glob,
+            name_regex,
+            phys: self.phys,
+            uniq: self.uniq,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            bustype: self.bustype,
+            version: self.version,
+            required_events: self.required_events.into_iter().map(Into::into).collect(),
+            forbidden_events: self.forbidden_events.into_iter().map(Into::into).collect(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceConfigFile {
+    #[serde(flatten)]
+    device_match: DeviceMatchConfig,
+
+    /// Remap every matching device, rather than only the first
+    #[serde(default)]
+    match_all: bool,
+
+    #[serde(default)]
+    merge: Vec<DeviceMatchConfig>,
+
+    /// Default tapping term for dual role keys, in milliseconds
+    #[serde(default)]
+    tapping_term_ms: Option<u64>,
+
+    #[serde(default)]
+    dual_role: Vec<DualRoleConfig>,
+
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+
+    /// Controls how held keys repeat
+    #[serde(default)]
+    repeat: RepeatConfig,
+
+    #[serde(default, rename = "macro")]
+    macro_: Vec<MacroConfig>,
+
+    #[serde(default)]
+    command: Vec<CommandConfig>,
+
+    #[serde(default)]
+    text: Vec<TextConfig>,
+
+    /// Controls how `text` entries are typed
+    #[serde(default)]
+    text_input: TextInputConfig,
+
+    #[serde(default)]
+    tap_dance: Vec<TapDanceConfig>,
+
+    #[serde(default)]
+    one_shot: Vec<OneShotConfig>,
+
+    #[serde(default)]
+    layer_switch: Vec<LayerSwitchConfig>,
+
+    #[serde(default)]
+    leader: Vec<LeaderConfig>,
+
+    /// Default combo term, in milliseconds
+    #[serde(default)]
+    combo_term_ms: Option<u64>,
+
+    #[serde(default)]
+    combo: Vec<ComboConfig>,
+
+    /// Default sequence term, in milliseconds
+    #[serde(default)]
+    sequence_term_ms: Option<u64>,
+
+    #[serde(default)]
+    sequence: Vec<SequenceConfig>,
+
+    #[serde(default)]
+    wheel_to_key: Vec<WheelToKeyConfig>,
+
+    #[serde(default)]
+    key_to_wheel: Vec<KeyToWheelConfig>,
+
+    #[serde(default)]
+    mouse_key: Vec<MouseKeyConfig>,
+
+    /// Controls how fast mouse keys move the pointer
+    #[serde(default)]
+    mouse_keys: MouseKeySettingsConfig,
+
+    #[serde(default)]
+    button_scroll: Vec<ButtonScrollConfig>,
+
+    #[serde(default)]
+    gesture: Vec<GestureConfig>,
+
+    #[serde(default)]
+    relative: Vec<RelativeConfig>,
+
+    #[serde(default)]
+    abs_to_key: Vec<AbsToKeyConfig>,
+
+    #[serde(default)]
+    layer: HashMap<String, LayerConfig>,
+}
+
+impl DeviceConfigFile {
+    fn into_device_config(self) -> anyhow::Result<DeviceConfig> {
+        if self.match_all && !self.merge.is_empty() {
+            return Err(ConfigError::MergeWithMatchAll.into());
+        }
+        let mut merge = vec![];
+        for device_match in self.merge {
+            let device_match = device_match.into_device_match()?;
+            if device_match.is_empty() {
+                return Err(ConfigError::EmptyMerge.into());
+            }
+            merge.push(device_match);
+        }
+        let tapping_term = self
+            .tapping_term_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_TAPPING_TERM);
+        let combo_term = self
+            .combo_term_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_COMBO_TERM);
+        let repeat = AutoRepeat::try_from(self.repeat)?;
+        let mut mappings = vec![];
+        for combo in self.combo {
+            mappings.push(combo.into_mapping(combo_term)?);
+        }
+        let sequence_term = self
+            .sequence_term_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_SEQUENCE_TERM);
+        for sequence in self.sequence {
+            mappings.push(sequence.into_mapping(sequence_term)?);
+        }
+        let text_layout: TextLayout = self.text_input.into();
+        for leader in self.leader {
+            mappings.push(leader.into_mapping(&text_layout)?);
+        }
+        for dual in self.dual_role {
+            mappings.push(dual.into_mapping(tapping_term)?);
+        }
+        for dance in self.tap_dance {
+            mappings.push(dance.into_mapping(tapping_term)?);
+        }
+        for oneshot in self.one_shot {
+            mappings.push(oneshot.into_mapping(tapping_term));
+        }
+        for switch in self.layer_switch {
+            mappings.push(switch.into());
+        }
+        for macro_config in self.macro_ {
+            mappings.push(macro_config.into());
+        }
+        for command in self.command {
+            mappings.push(command.into());
+        }
+        for text in self.text {
+            mappings.push(text.into_mapping(&text_layout)?);
+        }
+        for wheel in self.wheel_to_key {
+            mappings.push(wheel.into());
+        }
+        for key in self.key_to_wheel {
+            mappings.push(key.into());
+        }
+        for key in self.mouse_key {
+            mappings.push(key.into());
+        }
+        for scroll in self.button_scroll {
+            mappings.push(scroll.into());
+        }
+        for gesture in self.gesture {
+            mappings.push(gesture.into_mapping(&text_layout)?);
+        }
+        for relative in self.relative {
+            mappings.push(relative.into());
+        }
+        for abs in self.abs_to_key {
+            mappings.push(abs.into());
+        }
+        for remap in self.remap {
+            mappings.push(remap.into_mapping(&repeat.settings)?);
+        }
+        let mut layers = HashMap::new();
+        for (name, layer) in self.layer {
+            layers.insert(name, layer.into_mappings(&text_layout, &repeat.settings)?);
+        }
+
+        for map in mappings.iter().chain(layers.values().flatten()) {
+            for layer in map.referenced_layers() {
+                if !layers.contains_key(layer) {
+                    return Err(ConfigError::UnknownLayer(layer.to_string()).into());
+                }
+            }
+        }
+
+        Ok(DeviceConfig {
+            device_match: self.device_match.into_device_match()?,
+            match_all: self.match_all,
+            merge,
+            mappings,
+            layers,
+            repeat,
+            mouse_keys: self.mouse_keys.into(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    /// The keys at the top level of the file describe a device of
+    /// their own
+    #[serde(flatten)]
+    top_level: DeviceConfigFile,
+
+    #[serde(default)]
+    device: Vec<DeviceConfigFile>,
 }
@@ -1,10 +1,19 @@
+use crate::command::spawn_command;
+use crate::deviceinfo::VIRTUAL_DEVICE_NAME_PREFIX;
 use crate::mapping::*;
 use anyhow::*;
-use evdev_rs::{Device, DeviceWrapper, GrabMode, InputEvent, ReadFlag, TimeVal, UInputDevice};
+use evdev_rs::enums::EV_MSC;
+use evdev_rs::{
+    AbsInfo, Device, DeviceWrapper, EnableCodeData, GrabMode, InputEvent, ReadFlag, TimeVal,
+    UInputDevice,
+};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 #[derive(Clone, Copy, Debug)]
 enum KeyEventType {
@@ -36,6 +45,9 @@ impl KeyEventType {
 
 fn timeval_diff(newer: &TimeVal, older: &TimeVal) -> Duration {
     const MICROS_PER_SECOND: libc::time_t = 1000000;
+    if newer <= older {
+        return Duration::ZERO;
+    }
     let secs = newer.tv_sec - older.tv_sec;
     let usecs = newer.tv_usec - older.tv_usec;
 
@@ -48,92 +60,1477 @@ fn timeval_diff(newer: &TimeVal, older: &TimeVal) -> Duration {
     Duration::from_micros(((secs * MICROS_PER_SECOND) + usecs) as u64)
 }
 
+fn timeval_add(time: &TimeVal, duration: Duration) -> TimeVal {
+    TimeVal::new(
+        time.tv_sec + duration.as_secs() as libc::time_t,
+        time.tv_usec + duration.subsec_micros() as libc::suseconds_t,
+    )
+}
+
+/// Returns the current time, using the same clock as the kernel
+/// uses to timestamp input events
+fn timeval_now() -> TimeVal {
+    TimeVal::try_from(SystemTime::now()).unwrap_or(TimeVal::new(0, 0))
+}
+
+/// Wait for any of `files` to become readable, or for `timeout`
+/// to elapse.  Returns whether each of the files is readable.
+pub fn wait_for_input(files: &[&File], timeout: Option<Duration>) -> Result<Vec<bool>> {
+    let mut pfds: Vec<libc::pollfd> = files
+        .iter()
+        .map(|file| libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // Round up so that we don't wake up just before a deadline
+    // and then spin until it arrives
+    let timeout = match timeout {
+        Some(t) => t.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    let res = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout) };
+    if res < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(vec![false; files.len()]);
+        }
+        return Err(err).context("polling input devices");
+    }
+    Ok(pfds.iter().map(|pfd| pfd.revents != 0).collect())
+}
+
+/// A dual role key that is waiting to find out whether it
+/// is being tapped or held
+#[derive(Debug, Clone)]
+struct PendingDualRole {
+    code: KeyCode,
+    /// If still held at this time, the key becomes a hold
+    deadline: TimeVal,
+    interrupt: InterruptPolicy,
+    /// Events for other keys that are being held back until
+    /// the decision has been made
+    buffered: Vec<InputEvent>,
+    /// The keys whose press is in `buffered`
+    buffered_keys: HashSet<KeyCode>,
+}
+
+/// A `Mapping::OneShot` key that has been tapped, and is waiting
+/// to be applied to the next key press
+#[derive(Debug, Clone)]
+struct ArmedOneShot {
+    input: KeyCode,
+    output: Vec<KeyCode>,
+    /// If no key has been pressed by this time, the modifier is dropped
+    deadline: Option<TimeVal>,
+    /// Set while the key press that the modifier applies
+    /// to is being processed
+    applied: bool,
+}
+
+/// A `Mapping::TapDance` key that is counting taps
+#[derive(Debug, Clone)]
+struct TapDanceState {
+    input: KeyCode,
+    steps: Vec<TapDanceStep>,
+    tapping_term: Duration,
+    /// The number of times the key has been pressed
+    count: usize,
+    held: bool,
+    /// When this time is reached, the dance is over.  If the key
+    /// is still held, it becomes a hold, otherwise a tap.
+    deadline: TimeVal,
+}
+
+impl TapDanceState {
+    fn step(&self) -> &TapDanceStep {
+        &self.steps[self.count.min(self.steps.len()) - 1]
+    }
+}
+
+/// A `Mapping::Leader` key that has been pressed, and is capturing
+/// the keys that follow it
+#[derive(Debug, Clone)]
+struct LeaderCapture {
+    input: KeyCode,
+    timeout: Duration,
+    sequences: Vec<LeaderSequence>,
+    /// The keys that have been pressed so far
+    keys: Vec<KeyCode>,
+    /// The captured events, which are replayed if no sequence matches
+    events: Vec<InputEvent>,
+    /// If no key has been pressed by this time, the capture is abandoned
+    deadline: TimeVal,
+}
+
+/// Keys that may be the start of a `Mapping::Sequence`, held
+/// back until it is known whether the sequence is being typed
+#[derive(Debug, Clone)]
+struct PendingSequence {
+    /// The keys that have been pressed so far
+    keys: Vec<KeyCode>,
+    /// All events that arrived since the first key was pressed,
+    /// in their original order
+    events: Vec<InputEvent>,
+    /// If the next key hasn't been pressed by this time, the
+    /// events are passed on as though there were no sequence
+    deadline: TimeVal,
+}
+
+/// Presses of keys that may be part of a `Mapping::Combo`, held
+/// back until it is known whether a combo is being pressed
+#[derive(Debug, Clone)]
+struct PendingCombo {
+    keys: HashSet<KeyCode>,
+    events: Vec<InputEvent>,
+    /// If the combo hasn't been completed by this time, the
+    /// keys are passed on as regular key presses
+    deadline: TimeVal,
+}
+
+/// A `Mapping::Combo` that has been triggered
+#[derive(Debug, Clone)]
+struct ActiveCombo {
+    /// The input keys that are still held
+    held: HashSet<KeyCode>,
+    /// Emptied when the first of the input keys is released
+    output: Vec<KeyCode>,
+}
+
+/// The output key that is being auto-repeated
+#[derive(Debug, Clone)]
+struct RepeatingKey {
+    key: KeyCode,
+    interval: Duration,
+    /// When the next repeat event is due
+    next: TimeVal,
+}
+
+/// The motion of the pointer while mouse keys are held
+#[derive(Debug, Clone)]
+struct PointerMotion {
+    /// When the first of the mouse keys was pressed
+    started: TimeVal,
+    /// When the pointer is next due to move
+    next: TimeVal,
+    /// The fractions of a pixel that have yet to be moved
+    remainder: (f64, f64),
+}
+
+/// A `Mapping::ButtonScroll` button that is held, turning pointer
+/// motion into scrolling
+#[derive(Debug, Clone)]
+struct ButtonScrollState {
+    /// The press of the button, which is passed on if the
+    /// pointer doesn't move before the button is released
+    press: InputEvent,
+    distance: u32,
+    moved: bool,
+    /// Scrolling along the x and y axes that has yet to be emitted,
+    /// in high resolution units
+    remainder: (f64, f64),
+    /// High resolution scrolling that has been emitted, but doesn't
+    /// yet add up to a notch on the low resolution axes
+    partial: (i32, i32),
+}
+
+/// A `Mapping::Gesture` button that is held, accumulating
+/// pointer motion until it is released
+#[derive(Debug, Clone)]
+struct GestureState {
+    /// The press of the button, which is passed on if no
+    /// gesture applies when the button is released
+    press: InputEvent,
+    /// The total motion along the x and y axes
+    motion: (i64, i64),
+}
+
+/// A `Mapping::AbsToKey` whose thresholds have been resolved
+/// against the range of its axis
+#[derive(Debug, Clone)]
+struct AbsTrigger {
+    axis: EV_ABS,
+    /// 1 or -1, so that pushing the axis in the direction of the
+    /// mapping always increases `sign * value`
+    sign: i64,
+    /// `output` is pressed when `sign * value` reaches this
+    press_at: i64,
+    /// `output` is released when `sign * value` falls below
+    /// `press_at - hysteresis`
+    hysteresis: i64,
+    output: Vec<KeyCode>,
+    active: bool,
+}
+
+impl AbsTrigger {
+    /// Resolves the thresholds of a `Mapping::AbsToKey` from the
+    /// absinfo of its axis on `input`
+    fn new(
+        input: &Device,
+        axis: EV_ABS,
+        direction: AxisDirection,
+        threshold: Option<i32>,
+        output: Vec<KeyCode>,
+    ) -> Result<Self> {
+        let info = input
+            .abs_info(&EventCode::EV_ABS(axis))
+            .with_context(|| format!("the device doesn't have the axis {axis:?}"))?;
+        Ok(Self::with_abs_info(
+            &info, axis, direction, threshold, output,
+        ))
+    }
+
+    /// Resolves the thresholds from `info`.  When no threshold is
+    /// given, the default is kept out of the flat region around the
+    /// center, which is a dead zone.  The larger of flat and fuzz is
+    /// used as the hysteresis that prevents a noisy axis from
+    /// chattering, but never so much that the axis can't be released.
+    fn with_abs_info(
+        info: &AbsInfo,
+        axis: EV_ABS,
+        direction: AxisDirection,
+        threshold: Option<i32>,
+        output: Vec<KeyCode>,
+    ) -> Self {
+        let sign = match direction {
+            AxisDirection::Positive => 1,
+            AxisDirection::Negative => -1,
+        };
+        let center = (i64::from(info.minimum) + i64::from(info.maximum)) / 2;
+        let (start, end) = match direction {
+            AxisDirection::Positive => (i64::from(info.minimum), i64::from(info.maximum)),
+            AxisDirection::Negative => (i64::from(info.maximum), i64::from(info.minimum)),
+        };
+        let flat = i64::from(info.flat);
+        let press_at = match threshold {
+            Some(threshold) => sign * i64::from(threshold),
+            // Round away from the center, so that an axis with a
+            // range of -1 to 1, such as a hat, triggers at 1
+            None => (sign * center + (sign * (end - center) + 1) / 2).max(sign * center + flat + 1),
+        };
+        let hysteresis = flat
+            .max(i64::from(info.fuzz))
+            .min(press_at - sign * start - 1)
+            .max(0);
+        Self {
+            axis,
+            sign,
+            press_at,
+            hysteresis,
+            output,
+            active: false,
+        }
+    }
+
+    /// Updates the state of the trigger from a new value of its axis.
+    /// Returns true if the state changed.
+    fn update(&mut self, value: i32) -> bool {
+        let value = self.sign * i64::from(value);
+        let active = if self.active {
+            value >= self.press_at - self.hysteresis
+        } else {
+            value >= self.press_at
+        };
+        let changed = active != self.active;
+        self.active = active;
+        changed
+    }
+}
+
+/// A layer that has been activated by a `LayerMode::OneShot` key
+#[derive(Debug, Clone)]
+struct OneShotLayer {
+    layer: String,
+    /// The key that the layer is being applied to, once it
+    /// has been pressed.  The layer is deactivated when that
+    /// key is released.
+    key: Option<KeyCode>,
+}
+
+/// Receives the events produced by an `InputMapper`
+trait EventSink {
+    fn write_event(&self, event: &InputEvent) -> std::io::Result<()>;
+}
+
+impl EventSink for UInputDevice {
+    fn write_event(&self, event: &InputEvent) -> std::io::Result<()> {
+        UInputDevice::write_event(self, event)
+    }
+}
+
 pub struct InputMapper {
-    input: Device,
-    output: UInputDevice,
+    /// The grabbed devices, whose events are remapped as though they
+    /// came from a single device.  The output device is modelled on
+    /// the first of these.
+    inputs: Vec<Device>,
+    output: Box<dyn EventSink>,
     /// If present in this map, the key is down since the instant
     /// of its associated value
     input_state: HashMap<KeyCode, TimeVal>,
 
     mappings: Vec<Mapping>,
+    layers: HashMap<String, Vec<Mapping>>,
+
+    /// Layers activated by `LayerMode::Toggle` keys, in the
+    /// order in which they were activated
+    toggled_layers: Vec<String>,
+    oneshot_layer: Option<OneShotLayer>,
+
+    oneshot_mods: Vec<ArmedOneShot>,
+
+    /// Macro steps that are waiting to be performed
+    macro_queue: VecDeque<MacroStep>,
+    /// When set, the macro is paused by a delay step until this time
+    macro_resume_at: Option<TimeVal>,
+    /// Keys that have been pressed, but not yet released, by a macro
+    macro_keys: HashSet<KeyCode>,
+    /// Release-triggered chords that have been completed, and will
+    /// fire when any of their keys is released
+    armed_triggers: Vec<(HashSet<KeyCode>, Action)>,
+
+    tap_dance: Option<TapDanceState>,
+    /// A tap dance key that has been resolved as a hold, and
+    /// the action that applies while it remains held
+    tap_dance_hold: Option<(KeyCode, Action)>,
 
     /// The most recent candidate for a tap function is held here
     tapping: Option<KeyCode>,
 
+    /// A `hold_on_timeout` dual role key that hasn't yet been
+    /// resolved as either a tap or a hold
+    pending_dual_role: Option<PendingDualRole>,
+
+    leader: Option<LeaderCapture>,
+    /// Keys that completed a leader sequence or a `Mapping::Sequence`
+    /// while held; their remaining events are discarded
+    suppressed_keys: HashSet<KeyCode>,
+
+    pending_sequence: Option<PendingSequence>,
+
+    pending_combo: Option<PendingCombo>,
+    active_combos: Vec<ActiveCombo>,
+
+    repeat: AutoRepeat,
+    repeating: Option<RepeatingKey>,
+
+    mouse_keys: MouseKeySettings,
+    pointer: Option<PointerMotion>,
+
+    button_scroll: Option<ButtonScrollState>,
+    gesture: Option<GestureState>,
+    /// The fractional motion left over by `Mapping::Relative`
+    /// transforms, for each output axis
+    relative_remainders: HashMap<EV_REL, f64>,
+
+    abs_triggers: Vec<AbsTrigger>,
+
     output_keys: HashSet<KeyCode>,
 }
 
+/// Opens the input device at `path`.  The device is opened non-blocking
+/// so that the read loop can wake up to service timers while no input
+/// is arriving.
+fn open_input(path: &Path) -> Result<Device> {
+    let f = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .context(format!("opening {}", path.display()))?;
+    Device::new_from_file(f)
+        .with_context(|| format!("failed to create new Device from file {}", path.display()))
+}
+
+/// Enables the key, relative, absolute and misc events that `other`
+/// reports but that `input` doesn't.  Absolute axes take their range
+/// from `other`.
+fn enable_capabilities_of(input: &mut Device, other: &Device) -> Result<()> {
+    let codes = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
+        .iter()
+        .chain(EventCode::EV_REL(EV_REL::REL_X).iter())
+        .chain(EventCode::EV_ABS(EV_ABS::ABS_X).iter())
+        .chain(EventCode::EV_MSC(EV_MSC::MSC_SERIAL).iter());
+    for code in codes {
+        if !other.has(code) || input.has(code) {
+            continue;
+        }
+        let data = match code {
+            EventCode::EV_ABS(_) => other.abs_info(&code).map(EnableCodeData::AbsInfo),
+            _ => None,
+        };
+        input
+            .enable_event_code(&code, data)
+            .context(format!("enable {}", code))?;
+    }
+    Ok(())
+}
+
 fn enable_key_code(input: &mut Device, key: KeyCode) -> Result<()> {
     input
-        .enable(EventCode::EV_KEY(key.clone()))
+        .enable(EventCode::EV_KEY(key))
         .context(format!("enable key {:?}", key))?;
     Ok(())
 }
 
+/// Enables the low and high resolution axes that scroll in `direction`
+fn enable_wheel(input: &mut Device, direction: WheelDirection) -> Result<()> {
+    let (axis, hi_res_axis, _) = direction.axes();
+    for axis in [axis, hi_res_axis] {
+        input
+            .enable(EventCode::EV_REL(axis))
+            .context(format!("enable axis {:?}", axis))?;
+    }
+    Ok(())
+}
+
+fn enable_action_key_codes(input: &mut Device, action: &Action) -> Result<()> {
+    match action {
+        Action::Keys(keys) => {
+            for k in keys {
+                enable_key_code(input, *k)?;
+            }
+        }
+        Action::Macro(steps) => {
+            for step in steps {
+                match step {
+                    MacroStep::Press(k) | MacroStep::Release(k) | MacroStep::Tap(k) => {
+                        enable_key_code(input, *k)?;
+                    }
+                    MacroStep::Delay(_) => {}
+                }
+            }
+        }
+        Action::Wheel(direction) => enable_wheel(input, *direction)?,
+        Action::Layer(_) | Action::Command(_) => {}
+    }
+    Ok(())
+}
+
 impl InputMapper {
-    pub fn create_mapper<P: AsRef<Path>>(path: P, mappings: Vec<Mapping>) -> Result<Self> {
+    /// Grabs the device at `path`, along with `merged_paths`, and
+    /// creates an output device that applies the mappings of `config`
+    pub fn create_mapper<P: AsRef<Path>>(
+        path: P,
+        merged_paths: &[P],
+        config: &DeviceConfig,
+    ) -> Result<Self> {
         let path = path.as_ref();
-        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
-        let mut input = Device::new_from_file(f)
-            .with_context(|| format!("failed to create new Device from file {}", path.display()))?;
+        let mut input = open_input(path)?;
 
-        input.set_name(&format!("evremap Virtual input for {}", path.display()));
+        input.set_name(&format!("{VIRTUAL_DEVICE_NAME_PREFIX}{}", path.display()));
+
+        // The output device is modelled on `input`, so it needs to be
+        // able to produce the events of the merged devices too
+        let mut merged = vec![];
+        for merged_path in merged_paths {
+            let merged_path = merged_path.as_ref();
+            let device = open_input(merged_path)?;
+            enable_capabilities_of(&mut input, &device)
+                .with_context(|| format!("merging {}", merged_path.display()))?;
+            merged.push((merged_path, device));
+        }
 
         // Ensure that any remapped keys are supported by the generated output device
-        for map in &mappings {
+        let mut abs_triggers = vec![];
+        for map in config
+            .mappings
+            .iter()
+            .chain(config.layers.values().flatten())
+        {
             match map {
                 Mapping::DualRole { tap, hold, .. } => {
                     for t in tap {
-                        enable_key_code(&mut input, t.clone())?;
-                    }
-                    for h in hold {
-                        enable_key_code(&mut input, h.clone())?;
+                        enable_key_code(&mut input, *t)?;
                     }
+                    enable_action_key_codes(&mut input, hold)?;
                 }
                 Mapping::Remap { output, .. } => {
                     for o in output {
-                        enable_key_code(&mut input, o.clone())?;
+                        enable_key_code(&mut input, *o)?;
+                    }
+                }
+                Mapping::OneShot { output, .. }
+                | Mapping::Combo { output, .. }
+                | Mapping::Sequence { output, .. }
+                | Mapping::WheelToKey { output, .. } => {
+                    for o in output {
+                        enable_key_code(&mut input, *o)?;
+                    }
+                }
+                Mapping::TapDance { steps, .. } => {
+                    for step in steps {
+                        for t in &step.tap {
+                            enable_key_code(&mut input, *t)?;
+                        }
+                        if let Some(hold) = &step.hold {
+                            enable_action_key_codes(&mut input, hold)?;
+                        }
+                    }
+                }
+                Mapping::Trigger { action, .. } => {
+                    enable_action_key_codes(&mut input, action)?;
+                }
+                Mapping::Gesture { action, .. } => {
+                    enable_action_key_codes(&mut input, action)?;
+                }
+                Mapping::AbsToKey {
+                    axis,
+                    direction,
+                    threshold,
+                    output,
+                } => {
+                    for o in output {
+                        enable_key_code(&mut input, *o)?;
+                    }
+                    abs_triggers.push(
+                        AbsTrigger::new(&input, *axis, *direction, *threshold, output.clone())
+                            .with_context(|| {
+                                format!("mapping axis {axis:?} of {}", path.display())
+                            })?,
+                    );
+                }
+                Mapping::Relative { output, .. } => {
+                    input
+                        .enable(EventCode::EV_REL(*output))
+                        .context(format!("enable axis {:?}", output))?;
+                }
+                Mapping::Leader { sequences, .. } => {
+                    for seq in sequences {
+                        enable_action_key_codes(&mut input, &seq.action)?;
                     }
                 }
+                Mapping::MouseKey { .. } => {
+                    // The output device needs to look like a mouse
+                    // for the pointer motion to be recognized
+                    for axis in [EV_REL::REL_X, EV_REL::REL_Y] {
+                        input
+                            .enable(EventCode::EV_REL(axis))
+                            .context(format!("enable axis {:?}", axis))?;
+                    }
+                    for button in [KeyCode::BTN_LEFT, KeyCode::BTN_RIGHT, KeyCode::BTN_MIDDLE] {
+                        enable_key_code(&mut input, button)?;
+                    }
+                }
+                Mapping::ButtonScroll { .. } => {
+                    for direction in [WheelDirection::Up, WheelDirection::Right] {
+                        enable_wheel(&mut input, direction)?;
+                    }
+                }
+                Mapping::LayerSwitch { .. } => {}
             }
         }
 
+        // evremap generates its own repeats, so the kernel mustn't
+        // also do so for the output device
+        input
+            .disable(EventType::EV_REP)
+            .context("disable key repeat")?;
+
         let output = UInputDevice::create_from_device(&input)
             .context(format!("creating UInputDevice from {}", path.display()))?;
 
         input
             .grab(GrabMode::Grab)
             .context(format!("grabbing exclusive access on {}", path.display()))?;
+        let mut inputs = vec![input];
+        for (merged_path, mut device) in merged {
+            device.grab(GrabMode::Grab).context(format!(
+                "grabbing exclusive access on {}",
+                merged_path.display()
+            ))?;
+            inputs.push(device);
+        }
+
+        Ok(Self::new(inputs, Box::new(output), config, abs_triggers))
+    }
 
-        Ok(Self {
-            input,
+    fn new(
+        inputs: Vec<Device>,
+        output: Box<dyn EventSink>,
+        config: &DeviceConfig,
+        abs_triggers: Vec<AbsTrigger>,
+    ) -> Self {
+        Self {
+            inputs,
             output,
             input_state: HashMap::new(),
             output_keys: HashSet::new(),
             tapping: None,
-            mappings,
-        })
+            pending_dual_role: None,
+            mappings: config.mappings.clone(),
+            layers: config.layers.clone(),
+            toggled_layers: vec![],
+            oneshot_layer: None,
+            oneshot_mods: vec![],
+            macro_queue: VecDeque::new(),
+            macro_resume_at: None,
+            macro_keys: HashSet::new(),
+            armed_triggers: vec![],
+            tap_dance: None,
+            tap_dance_hold: None,
+            leader: None,
+            suppressed_keys: HashSet::new(),
+            pending_sequence: None,
+            pending_combo: None,
+            active_combos: vec![],
+            repeat: config.repeat.clone(),
+            repeating: None,
+            mouse_keys: config.mouse_keys,
+            pointer: None,
+            button_scroll: None,
+            gesture: None,
+            relative_remainders: HashMap::new(),
+            abs_triggers,
+        }
+    }
+
+    /// Returns the files of the grabbed devices, in the same
+    /// order as they are indexed by `read_pending_events`
+    pub fn files(&self) -> Vec<&File> {
+        self.inputs.iter().map(|input| input.file()).collect()
     }
 
-    pub fn run_mapper(&mut self) -> Result<()> {
-        log::info!("Going into read loop");
+    /// Process all events that can be read from `self.inputs[idx]`
+    /// without blocking.  Returns false if the device has been
+    /// removed from the system.
+    pub fn read_pending_events(&mut self, idx: usize) -> Result<bool> {
+        let mut syncing = false;
         loop {
-            let (status, event) = self
-                .input
-                .next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
-            match status {
-                evdev_rs::ReadStatus::Success => {
-                    if let EventCode::EV_KEY(ref key) = event.event_code {
-                        log::trace!("IN {:?}", event);
-                        self.update_with_event(&event, key.clone())?;
+            let flag = if syncing {
+                ReadFlag::SYNC
+            } else {
+                ReadFlag::NORMAL
+            };
+            let (status, event) = match self.inputs[idx].next_event(flag) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                    if syncing {
+                        // The device is in sync again
+                        syncing = false;
+                        continue;
+                    }
+                    return Ok(true);
+                }
+                Err(err) if err.raw_os_error() == Some(libc::ENODEV) => return Ok(false),
+                result => result?,
+            };
+            if status == evdev_rs::ReadStatus::Sync && !syncing {
+                // The kernel's buffer overflowed and events were lost.
+                // libevdev provides the events that bring its view of
+                // the device up to date, which are processed as usual.
+                log::warn!("Events from input {idx} were dropped; resynchronizing");
+                syncing = true;
+                continue;
+            }
+            self.process_event(&event)?;
+        }
+    }
+
+    /// Remaps a single event that was read from one of the inputs
+    fn process_event(&mut self, event: &InputEvent) -> Result<()> {
+        // Timers that expired before the event happened take effect
+        // first, even if the read loop hasn't woken up for them yet.
+        // Otherwise a release that arrives just after the tapping
+        // term would find the key still undecided.
+        self.handle_timeouts_at(&event.time)?;
+
+        match event.event_code {
+            EventCode::EV_KEY(key) => {
+                log::trace!("IN {:?}", event);
+                self.update_with_event(event, key)?;
+            }
+            EventCode::EV_REL(axis) => {
+                log::trace!("IN {:?}", event);
+                self.update_with_rel_event(event, axis)?;
+            }
+            EventCode::EV_ABS(axis) => {
+                log::trace!("IN {:?}", event);
+                self.update_with_abs_event(event, axis)?;
+            }
+            _ => {
+                log::trace!("PASSTHRU {:?}", event);
+                self.output.write_event(event)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how long it is until `handle_timeouts` has work
+    /// to do, if it has any
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.next_deadline()
+            .map(|deadline| timeval_diff(&deadline, &timeval_now()))
+    }
+
+    /// Returns the earliest time at which `handle_timeouts` has
+    /// work to do, if any
+    fn next_deadline(&self) -> Option<TimeVal> {
+        let pending = self
+            .pending_dual_role
+            .as_ref()
+            .map(|pending| pending.deadline);
+        let oneshots = self.oneshot_mods.iter().filter_map(|armed| armed.deadline);
+        let dance = self.tap_dance.as_ref().map(|dance| dance.deadline);
+        let leader = self.leader.as_ref().map(|capture| capture.deadline);
+        let sequence = self.pending_sequence.as_ref().map(|seq| seq.deadline);
+        let combo = self.pending_combo.as_ref().map(|combo| combo.deadline);
+        pending
+            .into_iter()
+            .chain(oneshots)
+            .chain(dance)
+            .chain(leader)
+            .chain(sequence)
+            .chain(combo)
+            .chain(self.repeating.as_ref().map(|repeating| repeating.next))
+            .chain(self.pointer.as_ref().map(|motion| motion.next))
+            .chain(self.macro_resume_at)
+            .min()
+    }
+
+    /// Perform any work whose deadline has passed
+    pub fn handle_timeouts(&mut self) -> Result<()> {
+        self.handle_timeouts_at(&timeval_now())
+    }
+
+    /// Perform any work whose deadline is at or before `now`
+    fn handle_timeouts_at(&mut self, now: &TimeVal) -> Result<()> {
+        if self
+            .leader
+            .as_ref()
+            .is_some_and(|capture| capture.deadline <= *now)
+        {
+            log::debug!("leader sequence timed out");
+            self.abandon_leader_sequence()?;
+        }
+        if self
+            .pending_sequence
+            .as_ref()
+            .is_some_and(|seq| seq.deadline <= *now)
+        {
+            self.resolve_sequence(now)?;
+        }
+        if self
+            .pending_combo
+            .as_ref()
+            .is_some_and(|combo| combo.deadline <= *now)
+        {
+            self.resolve_combo(now)?;
+        }
+        if let Some(pending) = &self.pending_dual_role {
+            if pending.deadline <= *now {
+                log::trace!("{:?} held past its tapping term", pending.code);
+                self.resolve_pending_dual_role(now)?;
+            }
+        }
+        if self
+            .tap_dance
+            .as_ref()
+            .is_some_and(|dance| dance.deadline <= *now)
+        {
+            self.resolve_tap_dance(now)?;
+        }
+        if self
+            .macro_resume_at
+            .is_some_and(|resume_at| resume_at <= *now)
+        {
+            self.run_macro_steps(now)?;
+        }
+        // Armed modifiers are not pressed in the output until they
+        // are applied, so they can simply be forgotten
+        self.oneshot_mods
+            .retain(|armed| armed.deadline.is_none_or(|deadline| deadline > *now));
+        if self
+            .pointer
+            .as_ref()
+            .is_some_and(|motion| motion.next <= *now)
+        {
+            self.move_pointer(now)?;
+        }
+        if let Some(repeating) = &mut self.repeating {
+            if repeating.next <= *now {
+                // Scheduling from `now` rather than from the previous
+                // repeat avoids a burst of repeats after a stall
+                repeating.next = timeval_add(now, repeating.interval);
+                let key = repeating.key;
+                self.emit_keys(&[key], now, KeyEventType::Repeat)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Perform a one-off action, such as one triggered by a chord
+    fn run_action(&mut self, action: &Action, time: &TimeVal) -> Result<()> {
+        match action {
+            Action::Keys(keys) => {
+                self.emit_keys(keys, time, KeyEventType::Press)?;
+                self.emit_keys(keys, time, KeyEventType::Release)?;
+            }
+            Action::Layer(layer) => self.switch_layer(layer, LayerMode::Toggle),
+            Action::Macro(steps) => {
+                self.macro_queue.extend(steps.iter().cloned());
+                if self.macro_resume_at.is_none() {
+                    self.run_macro_steps(time)?;
+                }
+            }
+            Action::Command(spec) => {
+                // A command that fails to start shouldn't stop
+                // the remapper
+                if let Err(err) = spawn_command(spec) {
+                    log::error!("{err:#}");
+                }
+            }
+            Action::Wheel(direction) => {
+                // One notch is 120 on the high resolution axes
+                let (axis, hi_res_axis, sign) = direction.axes();
+                self.output
+                    .write_event(&InputEvent::new(time, &EventCode::EV_REL(axis), sign))?;
+                self.output.write_event(&InputEvent::new(
+                    time,
+                    &EventCode::EV_REL(hi_res_axis),
+                    sign * 120,
+                ))?;
+                self.generate_sync_event(time)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `Mapping::WheelToKey` entries to a relative axis event,
+    /// passing it through if none apply
+    fn update_with_rel_event(&mut self, event: &InputEvent, axis: EV_REL) -> Result<()> {
+        if self.scroll_with_pointer(event, axis)? || self.track_gesture(event, axis) {
+            return Ok(());
+        }
+        let output = WheelDirection::from_event(axis, event.value)
+            .and_then(|(direction, hi_res)| Some((self.lookup_wheel_mapping(direction)?, hi_res)));
+        match output {
+            // Devices with a high resolution wheel report each notch on
+            // both axes, so only the low resolution one produces keys
+            Some((_, true)) => {}
+            Some((output, false)) => {
+                for _ in 0..event.value.unsigned_abs() {
+                    self.run_action(&Action::Keys(output.clone()), &event.time)?;
+                }
+            }
+            None => self.transform_relative(event, axis)?,
+        }
+        Ok(())
+    }
+
+    /// Updates the `Mapping::AbsToKey` entries for `axis`, passing
+    /// the event through if there are none
+    fn update_with_abs_event(&mut self, event: &InputEvent, axis: EV_ABS) -> Result<()> {
+        let mut mapped = false;
+        let mut changed = false;
+        for trigger in &mut self.abs_triggers {
+            if trigger.axis == axis {
+                mapped = true;
+                changed |= trigger.update(event.value);
+            }
+        }
+        if !mapped {
+            log::trace!("PASSTHRU {:?}", event);
+            self.output.write_event(event)?;
+        } else if changed {
+            self.compute_and_apply_keys(&event.time)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the `Mapping::Relative` entry for `axis`, if any, and
+    /// writes the resulting motion to the output device.  Entries
+    /// that require more held keys take precedence.
+    fn transform_relative(&mut self, event: &InputEvent, axis: EV_REL) -> Result<()> {
+        let transform = self
+            .mappings
+            .iter()
+            .filter_map(|map| match map {
+                Mapping::Relative {
+                    input,
+                    held,
+                    output,
+                    scale,
+                } if *input == axis
+                    && held.iter().all(|key| self.input_state.contains_key(key)) =>
+                {
+                    Some((held.len(), *output, *scale))
+                }
+                _ => None,
+            })
+            .max_by_key(|(held, _, _)| *held);
+        let Some((_, output, scale)) = transform else {
+            log::trace!("PASSTHRU {:?}", event);
+            self.output.write_event(event)?;
+            return Ok(());
+        };
+
+        // Carry the fractional part of the motion over to the next
+        // event, so that scaling down doesn't lose slow movements
+        let remainder = self.relative_remainders.entry(output).or_default();
+        *remainder += event.value as f64 * scale;
+        let value = remainder.trunc();
+        *remainder -= value;
+        if value != 0.0 {
+            self.output.write_event(&InputEvent::new(
+                &event.time,
+                &EventCode::EV_REL(output),
+                value as i32,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the output of the `Mapping::WheelToKey` entry that
+    /// applies to scrolling in `direction`.  Entries that require
+    /// more held keys take precedence.
+    fn lookup_wheel_mapping(&self, direction: WheelDirection) -> Option<Vec<KeyCode>> {
+        self.mappings
+            .iter()
+            .filter_map(|map| match map {
+                Mapping::WheelToKey {
+                    direction: d,
+                    held,
+                    output,
+                } if *d == direction
+                    && held.iter().all(|key| self.input_state.contains_key(key)) =>
+                {
+                    Some((held.len(), output))
+                }
+                _ => None,
+            })
+            .max_by_key(|(held, _)| *held)
+            .map(|(_, output)| output.clone())
+    }
+
+    /// Perform queued macro steps until the queue is empty,
+    /// or a delay step is reached
+    fn run_macro_steps(&mut self, time: &TimeVal) -> Result<()> {
+        self.macro_resume_at = None;
+        while let Some(step) = self.macro_queue.pop_front() {
+            match step {
+                MacroStep::Press(key) => {
+                    self.macro_keys.insert(key);
+                    self.emit_keys(&[key], time, KeyEventType::Press)?;
+                }
+                MacroStep::Release(key) => {
+                    self.macro_keys.remove(&key);
+                    self.emit_keys(&[key], time, KeyEventType::Release)?;
+                }
+                MacroStep::Tap(key) => {
+                    self.emit_keys(&[key], time, KeyEventType::Press)?;
+                    self.emit_keys(&[key], time, KeyEventType::Release)?;
+                }
+                MacroStep::Delay(delay) => {
+                    self.macro_resume_at.replace(timeval_add(time, delay));
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish counting taps for the current tap dance, if any, and
+    /// produce the outcome for the number of taps seen so far
+    fn resolve_tap_dance(&mut self, time: &TimeVal) -> Result<()> {
+        let Some(dance) = self.tap_dance.take() else {
+            return Ok(());
+        };
+        let step = dance.step().clone();
+        log::trace!(
+            "{:?} tap dance resolved after {} taps, held={}",
+            dance.input,
+            dance.count,
+            dance.held
+        );
+        if dance.held {
+            let hold = step.hold.unwrap_or(Action::Keys(step.tap));
+            self.tap_dance_hold.replace((dance.input, hold));
+            self.compute_and_apply_keys(time)?;
+        } else {
+            self.emit_keys(&step.tap, time, KeyEventType::Press)?;
+            self.emit_keys(&step.tap, time, KeyEventType::Release)?;
+        }
+        Ok(())
+    }
+
+    /// Update the tap dance state in response to a press of
+    /// a `Mapping::TapDance` key
+    fn press_tap_dance(
+        &mut self,
+        input: KeyCode,
+        steps: Vec<TapDanceStep>,
+        tapping_term: Duration,
+        time: &TimeVal,
+    ) {
+        let deadline = timeval_add(time, tapping_term);
+        match &mut self.tap_dance {
+            Some(dance) if dance.input == input => {
+                dance.count += 1;
+                dance.held = true;
+                dance.deadline = deadline;
+            }
+            _ => {
+                self.tap_dance.replace(TapDanceState {
+                    input,
+                    steps,
+                    tapping_term,
+                    count: 1,
+                    held: true,
+                    deadline,
+                });
+            }
+        }
+    }
+
+    /// Returns true if `code` is a `hold_on_timeout` dual role key
+    /// that hasn't yet been resolved as a tap or a hold
+    fn is_undecided(&self, code: KeyCode) -> bool {
+        self.pending_dual_role
+            .as_ref()
+            .is_some_and(|pending| pending.code == code)
+    }
+
+    /// Resolve a pending `hold_on_timeout` dual role key as a hold,
+    /// then process any events that were held back while it was
+    /// undecided
+    fn resolve_pending_dual_role(&mut self, time: &TimeVal) -> Result<()> {
+        if let Some(pending) = self.pending_dual_role.take() {
+            if self.tapping == Some(pending.code) {
+                self.cancel_pending_tap();
+            }
+            self.compute_and_apply_keys(time)?;
+            self.replay_events(pending.buffered, Self::update_core)?;
+        }
+        Ok(())
+    }
+
+    /// Feed events that were previously held back through `stage`,
+    /// which is the processing stage that follows the one that held
+    /// them back
+    fn replay_events(
+        &mut self,
+        events: Vec<InputEvent>,
+        stage: fn(&mut Self, &InputEvent, KeyCode) -> Result<()>,
+    ) -> Result<()> {
+        for event in events {
+            if let EventCode::EV_KEY(key) = event.event_code {
+                stage(self, &event, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Holds back the keys that follow a leader key, and performs the
+    /// action of the sequence that they complete.
+    /// Returns true if the event was consumed.
+    fn capture_leader_sequence(
+        &mut self,
+        event: &InputEvent,
+        code: KeyCode,
+        event_type: KeyEventType,
+    ) -> Result<bool> {
+        let capture = match self.leader.as_mut() {
+            Some(capture) if capture.input != code => capture,
+            Some(_) if matches!(event_type, KeyEventType::Press) => {
+                // Pressing the leader key again starts a new sequence,
+                // which mustn't lose the keys captured so far
+                self.abandon_leader_sequence()?;
+                return Ok(false);
+            }
+            _ => return Ok(false),
+        };
+
+        match event_type {
+            KeyEventType::Press => {
+                capture.keys.push(code);
+                capture.events.push(event.clone());
+                capture.deadline = timeval_add(&event.time, capture.timeout);
+            }
+            // Keys that were already down before the leader key
+            // are not affected by it
+            _ if !capture.keys.contains(&code) => return Ok(false),
+            KeyEventType::Release => {
+                capture.events.push(event.clone());
+                return Ok(true);
+            }
+            _ => return Ok(true),
+        }
+
+        if let Some(seq) = capture
+            .sequences
+            .iter()
+            .find(|seq| seq.keys == capture.keys)
+        {
+            let action = seq.action.clone();
+            log::debug!("leader sequence {:?} matched", capture.keys);
+            let capture = self.leader.take().expect("capture is present");
+            // Swallow the releases of any keys that are still held
+            let mut held = HashSet::new();
+            for event in &capture.events {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    match KeyEventType::from_value(event.value) {
+                        KeyEventType::Press => {
+                            held.insert(key);
+                        }
+                        KeyEventType::Release => {
+                            held.remove(&key);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            self.suppressed_keys.extend(held);
+            self.run_action(&action, &event.time)?;
+        } else if !capture
+            .sequences
+            .iter()
+            .any(|seq| seq.keys.starts_with(&capture.keys))
+        {
+            log::debug!("leader sequence {:?} didn't match", capture.keys);
+            self.abandon_leader_sequence()?;
+        }
+        Ok(true)
+    }
+
+    /// Stop capturing keys for a leader key, and process the
+    /// captured events as though there had been no leader key
+    fn abandon_leader_sequence(&mut self) -> Result<()> {
+        if let Some(capture) = self.leader.take() {
+            self.replay_events(capture.events, Self::update_sequences)?;
+        }
+        Ok(())
+    }
+
+    /// Complete the sequence formed by the pending keys if there is
+    /// one, otherwise pass on the held back events
+    fn resolve_sequence(&mut self, time: &TimeVal) -> Result<()> {
+        let Some(pending) = self.pending_sequence.take() else {
+            return Ok(());
+        };
+        let output = self.mappings.iter().find_map(|map| match map {
+            Mapping::Sequence { input, output, .. } if *input == pending.keys => {
+                Some(output.clone())
+            }
+            _ => None,
+        });
+        let Some(output) = output else {
+            return self.replay_events(pending.events, Self::update_combos);
+        };
+
+        log::debug!("sequence {:?} completed", pending.keys);
+        // The events of the sequence keys are replaced by the output,
+        // but those of other keys, such as the release of a key that
+        // was pressed before the sequence began, still apply
+        let mut held = HashSet::new();
+        let mut others = vec![];
+        for event in pending.events {
+            let EventCode::EV_KEY(key) = event.event_code else {
+                continue;
+            };
+            match KeyEventType::from_value(event.value) {
+                KeyEventType::Press => {
+                    held.insert(key);
+                }
+                KeyEventType::Release if held.contains(&key) => {
+                    held.remove(&key);
+                }
+                _ => others.push(event),
+            }
+        }
+        self.suppressed_keys.extend(held);
+        self.run_action(&Action::Keys(output), time)?;
+        self.replay_events(others, Self::update_combos)
+    }
+
+    /// Holds back the keys that may begin a sequence, until either
+    /// the sequence is completed or the next key is too late, then
+    /// passes events on to `update_combos`
+    fn update_sequences(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+        let event_type = KeyEventType::from_value(event.value);
+
+        if let Some(pending) = &self.pending_sequence {
+            match event_type {
+                KeyEventType::Press if event.time <= pending.deadline => {
+                    let mut keys = pending.keys.clone();
+                    keys.push(code);
+                    let mut complete = false;
+                    let mut extendable = false;
+                    let mut term = None;
+                    for map in &self.mappings {
+                        if let Mapping::Sequence {
+                            input,
+                            term: seq_term,
+                            ..
+                        } = map
+                        {
+                            if input.starts_with(&keys) {
+                                if input.len() == keys.len() {
+                                    complete = true;
+                                } else {
+                                    extendable = true;
+                                }
+                                term = term.max(Some(*seq_term));
+                            }
+                        }
+                    }
+                    if let Some(term) = term {
+                        let pending = self.pending_sequence.as_mut().expect("sequence is pending");
+                        pending.keys = keys;
+                        pending.events.push(event.clone());
+                        pending.deadline = timeval_add(&event.time, term);
+                        if complete && !extendable {
+                            self.resolve_sequence(&event.time)?;
+                        }
+                        return Ok(());
+                    }
+                }
+                // Releases are held back too, so that the order of
+                // events is preserved if the sequence isn't completed
+                KeyEventType::Release => {
+                    let pending = self.pending_sequence.as_mut().expect("sequence is pending");
+                    pending.events.push(event.clone());
+                    // A key that was pressed before the sequence began may
+                    // be repeating; it shouldn't go on doing so until the
+                    // release is replayed
+                    if !pending.keys.contains(&code) {
+                        self.repeating = None;
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            // Anything else ends the sequence, after which this
+            // event may begin another one
+            self.resolve_sequence(&event.time)?;
+            return self.update_sequences(event, code);
+        }
+
+        if matches!(event_type, KeyEventType::Press) {
+            let term = self
+                .mappings
+                .iter()
+                .filter_map(|map| match map {
+                    Mapping::Sequence { input, term, .. } if input[0] == code => Some(*term),
+                    _ => None,
+                })
+                .max();
+            if let Some(term) = term {
+                self.pending_sequence.replace(PendingSequence {
+                    keys: vec![code],
+                    events: vec![event.clone()],
+                    deadline: timeval_add(&event.time, term),
+                });
+                return Ok(());
+            }
+        }
+
+        self.update_combos(event, code)
+    }
+
+    /// Returns the combos whose input includes all of `keys`
+    fn combo_candidates<'a>(
+        &'a self,
+        keys: &'a HashSet<KeyCode>,
+    ) -> impl Iterator<Item = (&'a HashSet<KeyCode>, Duration)> + 'a {
+        self.mappings.iter().filter_map(move |map| match map {
+            Mapping::Combo { input, term, .. } if keys.is_subset(input) => Some((input, *term)),
+            _ => None,
+        })
+    }
+
+    /// Trigger the combo formed by the pending keys if there is one,
+    /// otherwise pass on the held back presses
+    fn resolve_combo(&mut self, time: &TimeVal) -> Result<()> {
+        let Some(pending) = self.pending_combo.take() else {
+            return Ok(());
+        };
+        let output = self.mappings.iter().find_map(|map| match map {
+            Mapping::Combo { input, output, .. } if *input == pending.keys => Some(output.clone()),
+            _ => None,
+        });
+        match output {
+            Some(output) => {
+                log::debug!("combo {:?} triggered", pending.keys);
+                self.active_combos.push(ActiveCombo {
+                    held: pending.keys,
+                    output,
+                });
+                self.cancel_pending_tap();
+                self.compute_and_apply_keys(time)?;
+            }
+            None => self.replay_events(pending.events, Self::update_core)?,
+        }
+        Ok(())
+    }
+
+    /// Holds back presses of keys that may form a combo, until
+    /// either the combo is completed or its term has elapsed,
+    /// then passes events on to `update_core`
+    fn update_combos(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+        let event_type = KeyEventType::from_value(event.value);
+
+        // The keys of a triggered combo are swallowed until released
+        if let Some(idx) = self
+            .active_combos
+            .iter()
+            .position(|combo| combo.held.contains(&code))
+        {
+            if matches!(event_type, KeyEventType::Release) {
+                let combo = &mut self.active_combos[idx];
+                combo.held.remove(&code);
+                combo.output.clear();
+                if combo.held.is_empty() {
+                    self.active_combos.remove(idx);
+                }
+                self.compute_and_apply_keys(&event.time)?;
+            }
+            return Ok(());
+        }
+
+        if let Some(pending) = &self.pending_combo {
+            if matches!(event_type, KeyEventType::Press)
+                && event.time <= pending.deadline
+                && !pending.keys.contains(&code)
+            {
+                let mut keys = pending.keys.clone();
+                keys.insert(code);
+                let first_press = pending.events[0].time;
+                let mut complete = false;
+                let mut extendable = false;
+                let mut term = None;
+                for (input, combo_term) in self.combo_candidates(&keys) {
+                    if *input == keys {
+                        complete = true;
                     } else {
-                        log::trace!("PASSTHRU {:?}", event);
-                        self.output.write_event(&event)?;
+                        extendable = true;
                     }
+                    term = term.max(Some(combo_term));
+                }
+                if let Some(term) = term {
+                    let pending = self.pending_combo.as_mut().expect("combo is pending");
+                    pending.keys = keys;
+                    pending.events.push(event.clone());
+                    pending.deadline = timeval_add(&first_press, term);
+                    if complete && !extendable {
+                        self.resolve_combo(&event.time)?;
+                    }
+                    return Ok(());
                 }
-                evdev_rs::ReadStatus::Sync => bail!("ReadStatus::Sync!"),
             }
+
+            // Anything else ends the combo, after which this event
+            // may need to be swallowed as part of it
+            self.resolve_combo(&event.time)?;
+            return self.update_combos(event, code);
         }
+
+        if matches!(event_type, KeyEventType::Press) {
+            let keys = HashSet::from([code]);
+            let term = self.combo_candidates(&keys).map(|(_, term)| term).max();
+            if let Some(term) = term {
+                self.pending_combo.replace(PendingCombo {
+                    keys,
+                    events: vec![event.clone()],
+                    deadline: timeval_add(&event.time, term),
+                });
+                return Ok(());
+            }
+        }
+
+        self.update_core(event, code)
+    }
+
+    /// Holds back events for other keys while a dual role key with
+    /// a buffering interrupt policy is undecided.
+    /// Returns true if the event was consumed.
+    fn buffer_interrupting_event(
+        &mut self,
+        event: &InputEvent,
+        code: KeyCode,
+        event_type: KeyEventType,
+    ) -> Result<bool> {
+        let pending = match self.pending_dual_role.as_mut() {
+            Some(pending)
+                if pending.code != code
+                    && pending.interrupt != InterruptPolicy::HoldOnOtherKeyPress =>
+            {
+                pending
+            }
+            _ => return Ok(false),
+        };
+
+        match event_type {
+            KeyEventType::Press => {
+                pending.buffered_keys.insert(code);
+            }
+            // Keys that were already down before the dual role key
+            // are not affected by it
+            _ if !pending.buffered_keys.contains(&code) => return Ok(false),
+            _ => {}
+        }
+        pending.buffered.push(event.clone());
+
+        if matches!(event_type, KeyEventType::Release)
+            && pending.interrupt == InterruptPolicy::PermissiveHold
+        {
+            // Another key was tapped entirely within the
+            // dual role key, so it must be a hold
+            self.resolve_pending_dual_role(&event.time)?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the names of the active layers, with the layer
+    /// that has the highest precedence last
+    fn active_layers(&self) -> Vec<&str> {
+        let mut layers: Vec<&str> = self.toggled_layers.iter().map(String::as_str).collect();
+
+        // Momentary layers stack up in the order that their keys were pressed
+        let mut held = vec![];
+        for map in &self.mappings {
+            let (input, layer) = match map {
+                Mapping::LayerSwitch {
+                    input,
+                    layer,
+                    mode: LayerMode::Momentary,
+                } => (input, layer),
+                Mapping::DualRole {
+                    input,
+                    hold: Action::Layer(layer),
+                    ..
+                } if !self.is_undecided(*input) => (input, layer),
+                _ => continue,
+            };
+            if let Some(pressed_at) = self.input_state.get(input) {
+                held.push((pressed_at, layer.as_str()));
+            }
+        }
+        if let Some((input, Action::Layer(layer))) = &self.tap_dance_hold {
+            if let Some(pressed_at) = self.input_state.get(input) {
+                held.push((pressed_at, layer.as_str()));
+            }
+        }
+        held.sort_by_key(|(pressed_at, _)| **pressed_at);
+        layers.extend(held.into_iter().map(|(_, layer)| layer));
+
+        if let Some(oneshot) = &self.oneshot_layer {
+            layers.push(&oneshot.layer);
+        }
+        layers
+    }
+
+    /// Returns the mappings of each active layer, from the top of
+    /// the layer stack down, followed by the base mappings
+    fn mapping_levels(&self) -> Vec<&[Mapping]> {
+        let mut levels: Vec<&[Mapping]> = self
+            .active_layers()
+            .into_iter()
+            .rev()
+            .filter_map(|layer| self.layers.get(layer))
+            .map(Vec::as_slice)
+            .collect();
+        levels.push(&self.mappings);
+        levels
     }
 
     /// Compute the effective set of keys that are pressed
@@ -144,40 +1541,111 @@ impl InputMapper {
         // First phase is to apply any DualRole mappings as they are likely to
         // be used to produce modifiers when held.
         for map in &self.mappings {
-            if let Mapping::DualRole { input, hold, .. } = map {
-                if keys.contains(input) {
-                    keys.remove(input);
-                    for h in hold {
-                        keys.insert(h.clone());
+            match map {
+                Mapping::DualRole { input, hold, .. } => {
+                    if keys.contains(input) {
+                        keys.remove(input);
+                        if self.is_undecided(*input) {
+                            // Produces nothing until we know whether
+                            // it is a tap or a hold
+                            continue;
+                        }
+                        if let Action::Keys(hold) = hold {
+                            for h in hold {
+                                keys.insert(*h);
+                            }
+                        }
+                    }
+                }
+                Mapping::OneShot { input, output, .. } => {
+                    if keys.remove(input) {
+                        keys.extend(output.iter().cloned());
+                    }
+                }
+                Mapping::TapDance { input, .. } => {
+                    if keys.remove(input) {
+                        if let Some((hold_input, Action::Keys(hold))) = &self.tap_dance_hold {
+                            if hold_input == input {
+                                keys.extend(hold.iter().cloned());
+                            }
+                        }
                     }
                 }
+                Mapping::LayerSwitch { input, .. } | Mapping::Leader { input, .. } => {
+                    // Only influences which layers are active,
+                    // or which keys are captured
+                    keys.remove(input);
+                }
+                Mapping::Remap { .. }
+                | Mapping::Trigger { .. }
+                | Mapping::Combo { .. }
+                | Mapping::Sequence { .. }
+                | Mapping::WheelToKey { .. }
+                | Mapping::MouseKey { .. }
+                | Mapping::ButtonScroll { .. }
+                | Mapping::Gesture { .. }
+                | Mapping::Relative { .. }
+                | Mapping::AbsToKey { .. } => {}
+            }
+        }
+
+        for armed in &self.oneshot_mods {
+            if armed.applied {
+                keys.extend(armed.output.iter().cloned());
             }
         }
 
         let mut keys_minus_remapped = keys.clone();
 
-        // Second pass to apply Remap items
-        for map in &self.mappings {
-            if let Mapping::Remap { input, output } = map {
-                if input.is_subset(&keys_minus_remapped) {
-                    for i in input {
-                        keys.remove(i);
-                        if !is_modifier(i) {
-                            keys_minus_remapped.remove(i);
-                        }
+        // Second pass to apply Remap items, starting with those
+        // in the layer at the top of the stack
+        for map in self.mapping_levels().into_iter().flatten() {
+            // A Trigger swallows its input keys, so it behaves
+            // like a Remap with no output here
+            let (input, output) = match map {
+                Mapping::Remap { input, output, .. } => (input, Some(output)),
+                Mapping::Trigger { input, .. } => (input, None),
+                Mapping::MouseKey { input, .. } => {
+                    // Only moves the pointer
+                    if keys_minus_remapped.remove(input) {
+                        keys.remove(input);
                     }
-                    for o in output {
-                        keys.insert(o.clone());
-                        // Outputs that apply are not visible as
-                        // inputs for later remap rules
-                        if !is_modifier(o) {
-                            keys_minus_remapped.remove(o);
-                        }
+                    continue;
+                }
+                _ => continue,
+            };
+            if input.is_subset(&keys_minus_remapped) {
+                for i in input {
+                    keys.remove(i);
+                    if !is_modifier(i) {
+                        keys_minus_remapped.remove(i);
+                    }
+                }
+                for o in output.into_iter().flatten() {
+                    keys.insert(*o);
+                    // Outputs that apply are not visible as
+                    // inputs for later remap rules
+                    if !is_modifier(o) {
+                        keys_minus_remapped.remove(o);
                     }
                 }
             }
         }
 
+        // Keys pressed by a macro stay down until the macro
+        // releases them
+        keys.extend(self.macro_keys.iter().cloned());
+
+        for combo in &self.active_combos {
+            keys.extend(combo.output.iter().cloned());
+        }
+
+        for trigger in &self.abs_triggers {
+            if trigger.active {
+                keys.extend(trigger.output.iter().cloned());
+            }
+        }
+
         keys
     }
 
@@ -214,69 +1682,328 @@ impl InputMapper {
             to_press.sort_by(modifiers_first);
             self.emit_keys(&to_press, time, KeyEventType::Press)?;
         }
-        Ok(())
+        Ok(())
+    }
+
+    /// Returns the DualRole or OneShot mapping for `code`, if any
+    fn lookup_tap_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        for map in &self.mappings {
+            match map {
+                Mapping::DualRole { input, .. } | Mapping::OneShot { input, .. }
+                    if *input == code =>
+                {
+                    // These have the highest precedence
+                    // so we've found our match
+                    return Some(map.clone());
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn lookup_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        for map in &self.mappings {
+            match map {
+                Mapping::DualRole { input, .. }
+                | Mapping::OneShot { input, .. }
+                | Mapping::TapDance { input, .. }
+                | Mapping::LayerSwitch { input, .. }
+                | Mapping::Leader { input, .. }
+                    if *input == code =>
+                {
+                    // These have the highest precedence
+                    // so we've found our match
+                    return Some(map.clone());
+                }
+                _ => {}
+            }
+        }
+
+        // Otherwise, the first layer with a matching Remap wins
+        self.mapping_levels()
+            .into_iter()
+            .find_map(|mappings| self.lookup_remap_mapping(mappings, code))
+    }
+
+    fn lookup_remap_mapping(&self, mappings: &[Mapping], code: KeyCode) -> Option<Mapping> {
+        let mut candidates = vec![];
+
+        for map in mappings {
+            match map {
+                Mapping::DualRole { .. }
+                | Mapping::OneShot { .. }
+                | Mapping::TapDance { .. }
+                | Mapping::LayerSwitch { .. }
+                | Mapping::Leader { .. }
+                | Mapping::Combo { .. }
+                | Mapping::Sequence { .. }
+                | Mapping::WheelToKey { .. }
+                | Mapping::MouseKey { .. }
+                | Mapping::ButtonScroll { .. }
+                | Mapping::Gesture { .. }
+                | Mapping::Relative { .. }
+                | Mapping::AbsToKey { .. } => {}
+                Mapping::Remap { input, .. } | Mapping::Trigger { input, .. } => {
+                    // Look for a mapping that includes the current key.
+                    // If part of a chord, all of its component keys must
+                    // also be pressed.
+                    let mut code_matched = false;
+                    let mut all_matched = true;
+                    for i in input {
+                        if *i == code {
+                            code_matched = true;
+                        } else if !self.input_state.contains_key(i) {
+                            all_matched = false;
+                            break;
+                        }
+                    }
+                    if code_matched && all_matched {
+                        candidates.push(map);
+                    }
+                }
+            }
+        }
+
+        // Any matches must be Remap or Trigger entries.  We want
+        // the one with the most active keys
+        let chord_len = |map: &Mapping| match map {
+            Mapping::Remap { input, .. } | Mapping::Trigger { input, .. } => input.len(),
+            _ => unreachable!(),
+        };
+        candidates.sort_by(|a, b| chord_len(a).cmp(&chord_len(b)).reverse());
+
+        candidates.first().map(|&m| m.clone())
+    }
+
+    pub fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+        let event_type = KeyEventType::from_value(event.value);
+        if self.suppressed_keys.contains(&code) {
+            if matches!(event_type, KeyEventType::Release) {
+                self.suppressed_keys.remove(&code);
+            }
+            return Ok(());
+        }
+        if matches!(event_type, KeyEventType::Repeat) {
+            // Repeats are generated by `handle_timeouts` instead
+            return Ok(());
+        }
+        if self.update_button_scroll(event, code, event_type)? {
+            return Ok(());
+        }
+        if self.update_gesture(event, code, event_type)? {
+            return Ok(());
+        }
+        self.update_leader(event, code)
+    }
+
+    /// Starts tracking a gesture when a `Mapping::Gesture` button is
+    /// pressed, and performs the gesture when it is released.
+    /// Returns true if the event was consumed.
+    fn update_gesture(
+        &mut self,
+        event: &InputEvent,
+        code: KeyCode,
+        event_type: KeyEventType,
+    ) -> Result<bool> {
+        if let Some(gesture) = &self.gesture {
+            if gesture.press.event_code != event.event_code {
+                return Ok(false);
+            }
+            if matches!(event_type, KeyEventType::Release) {
+                let gesture = self.gesture.take().expect("button is held");
+                match self.lookup_gesture(code, gesture.motion) {
+                    Some(action) => self.run_action(&action, &event.time)?,
+                    None => {
+                        self.replay_events(vec![gesture.press, event.clone()], Self::update_leader)?
+                    }
+                }
+            }
+            return Ok(true);
+        }
+
+        let is_gesture_button = self
+            .mappings
+            .iter()
+            .any(|map| matches!(map, Mapping::Gesture { button, .. } if *button == code));
+        if !is_gesture_button || !matches!(event_type, KeyEventType::Press) {
+            return Ok(false);
+        }
+        self.gesture.replace(GestureState {
+            press: event.clone(),
+            motion: (0, 0),
+        });
+        Ok(true)
     }
 
-    fn lookup_dual_role_mapping(&self, code: KeyCode) -> Option<Mapping> {
-        for map in &self.mappings {
-            if let Mapping::DualRole { input, .. } = map {
-                if *input == code {
-                    // A DualRole mapping has the highest precedence
-                    // so we've found our match
-                    return Some(map.clone());
+    /// Returns the action of the gesture that applies to `motion` of
+    /// the pointer while `code` was held.  Motion is classified by
+    /// its dominant axis, and when several gestures apply to that
+    /// direction, the one with the highest threshold wins.
+    fn lookup_gesture(&self, code: KeyCode, motion: (i64, i64)) -> Option<Action> {
+        let (x, y) = motion;
+        let (direction, distance) = if x.abs() >= y.abs() {
+            let direction = if x < 0 {
+                PointerDirection::Left
+            } else {
+                PointerDirection::Right
+            };
+            (direction, x.unsigned_abs())
+        } else {
+            let direction = if y < 0 {
+                PointerDirection::Up
+            } else {
+                PointerDirection::Down
+            };
+            (direction, y.unsigned_abs())
+        };
+        self.mappings
+            .iter()
+            .filter_map(|map| match map {
+                Mapping::Gesture {
+                    button,
+                    direction: d,
+                    threshold,
+                    action,
+                } if *button == code && *d == direction && distance >= u64::from(*threshold) => {
+                    Some((threshold, action))
                 }
-            }
-        }
-        None
+                _ => None,
+            })
+            .max_by_key(|(threshold, _)| **threshold)
+            .map(|(_, action)| action.clone())
     }
 
-    fn lookup_mapping(&self, code: KeyCode) -> Option<Mapping> {
-        let mut candidates = vec![];
+    /// Accumulates pointer motion while a `Mapping::Gesture` button
+    /// is held.  Returns true if the event was consumed.
+    fn track_gesture(&mut self, event: &InputEvent, axis: EV_REL) -> bool {
+        let Some(gesture) = self.gesture.as_mut() else {
+            return false;
+        };
+        match axis {
+            EV_REL::REL_X => gesture.motion.0 += i64::from(event.value),
+            EV_REL::REL_Y => gesture.motion.1 += i64::from(event.value),
+            _ => return false,
+        }
+        true
+    }
 
-        for map in &self.mappings {
-            match map {
-                Mapping::DualRole { input, .. } => {
-                    if *input == code {
-                        // A DualRole mapping has the highest precedence
-                        // so we've found our match
-                        return Some(map.clone());
-                    }
-                }
-                Mapping::Remap { input, .. } => {
-                    // Look for a mapping that includes the current key.
-                    // If part of a chord, all of its component keys must
-                    // also be pressed.
-                    let mut code_matched = false;
-                    let mut all_matched = true;
-                    for i in input {
-                        if *i == code {
-                            code_matched = true;
-                        } else if !self.input_state.contains_key(i) {
-                            all_matched = false;
-                            break;
-                        }
-                    }
-                    if code_matched && all_matched {
-                        candidates.push(map);
-                    }
+    /// Starts and stops button scrolling when a `Mapping::ButtonScroll`
+    /// button is pressed and released.
+    /// Returns true if the event was consumed.
+    fn update_button_scroll(
+        &mut self,
+        event: &InputEvent,
+        code: KeyCode,
+        event_type: KeyEventType,
+    ) -> Result<bool> {
+        if let Some(scroll) = &self.button_scroll {
+            if scroll.press.event_code != event.event_code {
+                return Ok(false);
+            }
+            if matches!(event_type, KeyEventType::Release) {
+                let scroll = self.button_scroll.take().expect("button is held");
+                if !scroll.moved {
+                    // It was a click rather than a scroll
+                    self.replay_events(vec![scroll.press, event.clone()], Self::update_leader)?;
                 }
             }
+            return Ok(true);
         }
 
-        // Any matches must be Remap entries.  We want the one
-        // with the most active keys
-        candidates.sort_by(|a, b| match (a, b) {
-            (Mapping::Remap { input: input_a, .. }, Mapping::Remap { input: input_b, .. }) => {
-                input_a.len().cmp(&input_b.len()).reverse()
-            }
-            _ => unreachable!(),
+        if !matches!(event_type, KeyEventType::Press) {
+            return Ok(false);
+        }
+        let distance = self.mappings.iter().find_map(|map| match map {
+            Mapping::ButtonScroll { button, distance } if *button == code => Some(*distance),
+            _ => None,
+        });
+        let Some(distance) = distance else {
+            return Ok(false);
+        };
+        self.button_scroll.replace(ButtonScrollState {
+            press: event.clone(),
+            distance,
+            moved: false,
+            remainder: (0.0, 0.0),
+            partial: (0, 0),
         });
+        Ok(true)
+    }
+
+    /// Converts pointer motion into scrolling while a
+    /// `Mapping::ButtonScroll` button is held.
+    /// Returns true if the event was consumed.
+    fn scroll_with_pointer(&mut self, event: &InputEvent, axis: EV_REL) -> Result<bool> {
+        let Some(scroll) = self.button_scroll.as_mut() else {
+            return Ok(false);
+        };
+        // Moving the pointer down scrolls down, which is a negative
+        // value on the vertical wheel axis
+        let (axis, hi_res_axis, value, remainder, partial) = match axis {
+            EV_REL::REL_X => (
+                EV_REL::REL_HWHEEL,
+                EV_REL::REL_HWHEEL_HI_RES,
+                event.value,
+                &mut scroll.remainder.0,
+                &mut scroll.partial.0,
+            ),
+            EV_REL::REL_Y => (
+                EV_REL::REL_WHEEL,
+                EV_REL::REL_WHEEL_HI_RES,
+                -event.value,
+                &mut scroll.remainder.1,
+                &mut scroll.partial.1,
+            ),
+            _ => return Ok(false),
+        };
+        if value != 0 {
+            scroll.moved = true;
+        }
+
+        // One notch is 120 on the high resolution axes
+        *remainder += value as f64 * 120.0 / scroll.distance as f64;
+        let hi_res = remainder.trunc();
+        *remainder -= hi_res;
+        let hi_res = hi_res as i32;
+        *partial += hi_res;
+        let notches = *partial / 120;
+        *partial -= notches * 120;
 
-        candidates.get(0).map(|&m| m.clone())
+        if hi_res != 0 {
+            self.output.write_event(&InputEvent::new(
+                &event.time,
+                &EventCode::EV_REL(hi_res_axis),
+                hi_res,
+            ))?;
+        }
+        if notches != 0 {
+            self.output.write_event(&InputEvent::new(
+                &event.time,
+                &EventCode::EV_REL(axis),
+                notches,
+            ))?;
+        }
+        Ok(true)
     }
 
-    pub fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+    /// Holds back the keys that follow a leader key, then passes
+    /// events on to `update_sequences`
+    fn update_leader(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+        let event_type = KeyEventType::from_value(event.value);
+        if self.capture_leader_sequence(event, code, event_type)? {
+            return Ok(());
+        }
+        self.update_sequences(event, code)
+    }
+
+    /// The final processing stage, which applies the mappings to
+    /// events that earlier stages have let through
+    fn update_core(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
         let event_type = KeyEventType::from_value(event.value);
+        if self.buffer_interrupting_event(event, code, event_type)? {
+            return Ok(());
+        }
         match event_type {
             KeyEventType::Release => {
                 let pressed_at = match self.input_state.remove(&code) {
@@ -287,29 +2014,196 @@ impl InputMapper {
                     Some(p) => p,
                 };
 
-                self.compute_and_apply_keys(&event.time)?;
+                if self
+                    .oneshot_layer
+                    .as_ref()
+                    .is_some_and(|oneshot| oneshot.key == Some(code))
+                {
+                    // The one-shot layer has been used up
+                    self.oneshot_layer = None;
+                }
 
-                if let Some(Mapping::DualRole { tap, .. }) =
-                    self.lookup_dual_role_mapping(code.clone())
+                if self
+                    .tap_dance_hold
+                    .as_ref()
+                    .is_some_and(|(input, _)| *input == code)
                 {
-                    // If released quickly enough, becomes a tap press.
-                    if let Some(tapping) = self.tapping.take() {
-                        if tapping == code
-                            && timeval_diff(&event.time, &pressed_at) <= Duration::from_millis(200)
-                        {
-                            self.emit_keys(&tap, &event.time, KeyEventType::Press)?;
-                            self.emit_keys(&tap, &event.time, KeyEventType::Release)?;
+                    self.tap_dance_hold = None;
+                }
+
+                // Fire any release-triggered chords that include this key
+                let mut fired = vec![];
+                self.armed_triggers.retain(|(input, action)| {
+                    if input.contains(&code) {
+                        fired.push(action.clone());
+                        false
+                    } else {
+                        true
+                    }
+                });
+                for action in fired {
+                    self.run_action(&action, &event.time)?;
+                }
+
+                if let Some(dance) = &mut self.tap_dance {
+                    if dance.input == code {
+                        // Wait for the next tap, unless there can't be one
+                        dance.held = false;
+                        dance.deadline = timeval_add(&event.time, dance.tapping_term);
+                        if dance.count >= dance.steps.len() {
+                            self.resolve_tap_dance(&event.time)?;
+                        }
+                    }
+                }
+
+                // Releasing an undecided dual role key makes it a tap
+                let undecided = if self.is_undecided(code) {
+                    self.pending_dual_role.take()
+                } else {
+                    None
+                };
+
+                self.compute_and_apply_keys(&event.time)?;
+
+                match self.lookup_tap_mapping(code) {
+                    Some(Mapping::DualRole {
+                        tap, tapping_term, ..
+                    }) => {
+                        // If released quickly enough, becomes a tap press.
+                        if let Some(tapping) = self.tapping.take() {
+                            if tapping == code
+                                && timeval_diff(&event.time, &pressed_at) <= tapping_term
+                            {
+                                self.emit_keys(&tap, &event.time, KeyEventType::Press)?;
+                                self.emit_keys(&tap, &event.time, KeyEventType::Release)?;
+                            }
+                        }
+                    }
+                    Some(Mapping::OneShot {
+                        output,
+                        tapping_term,
+                        timeout,
+                        ..
+                    }) => {
+                        // If released quickly enough, arms the modifier
+                        if let Some(tapping) = self.tapping.take() {
+                            if tapping == code
+                                && timeval_diff(&event.time, &pressed_at) <= tapping_term
+                            {
+                                let deadline = timeout.map(|t| timeval_add(&event.time, t));
+                                self.toggle_oneshot(code, output, deadline);
+                            }
                         }
                     }
+                    _ => {}
+                }
+
+                if let Some(pending) = undecided {
+                    self.replay_events(pending.buffered, Self::update_core)?;
                 }
             }
             KeyEventType::Press => {
-                self.input_state.insert(code.clone(), event.time.clone());
+                // Pressing any other key while a dual role key is
+                // undecided means that it is being used as a hold
+                self.resolve_pending_dual_role(&event.time)?;
+
+                // Similarly, pressing any other key ends a tap dance
+                if self
+                    .tap_dance
+                    .as_ref()
+                    .is_some_and(|dance| dance.input != code)
+                {
+                    self.resolve_tap_dance(&event.time)?;
+                }
+
+                self.input_state.insert(code, event.time);
+
+                let mapping = self.lookup_mapping(code);
+
+                // A one-shot layer applies to the next key that is pressed
+                if let Some(oneshot) = &mut self.oneshot_layer {
+                    if oneshot.key.is_none()
+                        && !matches!(mapping, Some(Mapping::LayerSwitch { .. }))
+                    {
+                        oneshot.key.replace(code);
+                    }
+                }
+
+                // Armed one-shot modifiers apply to the next regular key
+                // that is pressed
+                let apply_oneshots = !self.oneshot_mods.is_empty()
+                    && !is_modifier(&code)
+                    && !matches!(
+                        mapping,
+                        Some(
+                            Mapping::DualRole { .. }
+                                | Mapping::OneShot { .. }
+                                | Mapping::TapDance { .. }
+                                | Mapping::LayerSwitch { .. }
+                                | Mapping::Leader { .. }
+                        )
+                    );
+                if apply_oneshots {
+                    for armed in &mut self.oneshot_mods {
+                        armed.applied = true;
+                    }
+                }
 
-                match self.lookup_mapping(code.clone()) {
-                    Some(_) => {
+                match mapping {
+                    Some(mapping) => {
+                        if let Mapping::LayerSwitch { layer, mode, .. } = &mapping {
+                            self.switch_layer(layer, *mode);
+                        }
+                        if let Mapping::TapDance {
+                            steps,
+                            tapping_term,
+                            ..
+                        } = &mapping
+                        {
+                            self.press_tap_dance(code, steps.clone(), *tapping_term, &event.time);
+                        }
+                        if let Mapping::Leader {
+                            timeout, sequences, ..
+                        } = &mapping
+                        {
+                            log::debug!("leader {code:?} capturing");
+                            self.leader.replace(LeaderCapture {
+                                input: code,
+                                timeout: *timeout,
+                                sequences: sequences.clone(),
+                                keys: vec![],
+                                events: vec![],
+                                deadline: timeval_add(&event.time, *timeout),
+                            });
+                        }
+                        if let Mapping::DualRole {
+                            tapping_term,
+                            hold_on_timeout: true,
+                            interrupt,
+                            ..
+                        } = mapping
+                        {
+                            self.pending_dual_role.replace(PendingDualRole {
+                                code,
+                                deadline: timeval_add(&event.time, tapping_term),
+                                interrupt,
+                                buffered: vec![],
+                                buffered_keys: HashSet::new(),
+                            });
+                        }
                         self.compute_and_apply_keys(&event.time)?;
                         self.tapping.replace(code);
+
+                        // The chord keys have been swallowed by now, so they
+                        // won't modify the keys produced by the action
+                        if let Mapping::Trigger { input, action, on } = mapping {
+                            match on {
+                                TriggerEdge::Press => self.run_action(&action, &event.time)?,
+                                TriggerEdge::Release => {
+                                    self.armed_triggers.push((input, action));
+                                }
+                            }
+                        }
                     }
                     None => {
                         // Just pass it through
@@ -317,31 +2211,181 @@ impl InputMapper {
                         self.compute_and_apply_keys(&event.time)?;
                     }
                 }
-            }
-            KeyEventType::Repeat => {
-                match self.lookup_mapping(code.clone()) {
-                    Some(Mapping::DualRole { hold, .. }) => {
-                        self.emit_keys(&hold, &event.time, KeyEventType::Repeat)?;
-                    }
-                    Some(Mapping::Remap { output, .. }) => {
-                        let output: Vec<KeyCode> = output.iter().cloned().collect();
-                        self.emit_keys(&output, &event.time, KeyEventType::Repeat)?;
-                    }
-                    None => {
-                        // Just pass it through
-                        self.cancel_pending_tap();
-                        self.write_event_and_sync(event)?;
-                    }
+
+                if apply_oneshots {
+                    // The modifiers were pressed along with the key;
+                    // now release them
+                    self.oneshot_mods.clear();
+                    self.compute_and_apply_keys(&event.time)?;
                 }
             }
+            // Dropped by `update_with_event`
+            KeyEventType::Repeat => {}
             KeyEventType::Unknown(_) => {
                 self.write_event_and_sync(event)?;
             }
         }
 
+        self.update_mouse_keys(&event.time)?;
+        Ok(())
+    }
+
+    /// Returns the directions of the mouse keys that are held
+    fn pointer_directions(&self) -> Vec<PointerDirection> {
+        let levels = self.mapping_levels();
+        self.input_state
+            .keys()
+            .filter_map(|key| {
+                levels
+                    .iter()
+                    .flat_map(|mappings| mappings.iter())
+                    .find_map(|map| match map {
+                        Mapping::MouseKey { input, direction } if input == key => Some(*direction),
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    /// Starts moving the pointer when the first mouse key is pressed,
+    /// and stops when the last one is released
+    fn update_mouse_keys(&mut self, time: &TimeVal) -> Result<()> {
+        if self.pointer_directions().is_empty() {
+            self.pointer = None;
+        } else if self.pointer.is_none() {
+            self.pointer.replace(PointerMotion {
+                started: *time,
+                next: *time,
+                remainder: (0.0, 0.0),
+            });
+            self.move_pointer(time)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the pointer by the distance that it travels in one
+    /// interval at its current speed, and schedules the next move
+    fn move_pointer(&mut self, now: &TimeVal) -> Result<()> {
+        let (mut x, mut y) = (0, 0);
+        for direction in self.pointer_directions() {
+            let (dx, dy) = direction.vector();
+            x += dx;
+            y += dy;
+        }
+        // Opposing keys cancel each other out
+        let (x, y) = (x.signum(), y.signum());
+
+        let settings = self.mouse_keys;
+        let Some(motion) = self.pointer.as_mut() else {
+            return Ok(());
+        };
+        motion.next = timeval_add(now, settings.interval);
+        if x == 0 && y == 0 {
+            return Ok(());
+        }
+
+        let progress = if settings.acceleration_time.is_zero() {
+            1.0
+        } else {
+            timeval_diff(now, &motion.started).as_secs_f64()
+                / settings.acceleration_time.as_secs_f64()
+        };
+        let speed = settings.initial_speed
+            + (settings.max_speed - settings.initial_speed) * settings.curve.apply(progress);
+        let mut distance = speed * settings.interval.as_secs_f64();
+        if x != 0 && y != 0 {
+            // Move diagonally at the same speed
+            distance /= std::f64::consts::SQRT_2;
+        }
+
+        // Carry the fractional part of the distance over to the
+        // next move, so that slow speeds still move the pointer
+        motion.remainder.0 += x as f64 * distance;
+        motion.remainder.1 += y as f64 * distance;
+        let dx = motion.remainder.0.trunc();
+        let dy = motion.remainder.1.trunc();
+        motion.remainder.0 -= dx;
+        motion.remainder.1 -= dy;
+
+        for (axis, value) in [(EV_REL::REL_X, dx as i32), (EV_REL::REL_Y, dy as i32)] {
+            if value != 0 {
+                self.output
+                    .write_event(&InputEvent::new(now, &EventCode::EV_REL(axis), value))?;
+            }
+        }
+        if dx != 0.0 || dy != 0.0 {
+            self.generate_sync_event(now)?;
+        }
         Ok(())
     }
 
+    /// Update the layer state in response to a press of a
+    /// `LayerSwitch` key.  Momentary layers are derived from
+    /// `input_state` and need no additional state.
+    fn switch_layer(&mut self, layer: &str, mode: LayerMode) {
+        match mode {
+            LayerMode::Momentary => {}
+            LayerMode::Toggle => {
+                if let Some(idx) = self.toggled_layers.iter().position(|l| l == layer) {
+                    log::debug!("deactivating layer {layer}");
+                    self.toggled_layers.remove(idx);
+                } else {
+                    log::debug!("activating layer {layer}");
+                    self.toggled_layers.push(layer.to_string());
+                }
+            }
+            LayerMode::OneShot => {
+                self.oneshot_layer.replace(OneShotLayer {
+                    layer: layer.to_string(),
+                    key: None,
+                });
+            }
+        }
+    }
+
+    /// Arm a tapped one-shot modifier, or disarm it if it
+    /// was already armed
+    fn toggle_oneshot(&mut self, input: KeyCode, output: Vec<KeyCode>, deadline: Option<TimeVal>) {
+        if let Some(idx) = self
+            .oneshot_mods
+            .iter()
+            .position(|armed| armed.input == input)
+        {
+            log::debug!("disarming one-shot {input:?}");
+            self.oneshot_mods.remove(idx);
+        } else {
+            log::debug!("arming one-shot {input:?}");
+            self.oneshot_mods.push(ArmedOneShot {
+                input,
+                output,
+                deadline,
+                applied: false,
+            });
+        }
+    }
+
+    /// Returns how `key` should repeat when it is pressed in the
+    /// output, or `None` if it shouldn't repeat
+    fn repeat_settings(&self, key: KeyCode) -> Option<RepeatSettings> {
+        if self.repeat.exclude.contains(&key) {
+            return None;
+        }
+        // Use the settings of the remap entry that produced the key
+        for map in self.mapping_levels().into_iter().flatten() {
+            if let Mapping::Remap {
+                input,
+                output,
+                repeat,
+            } = map
+            {
+                if output.contains(&key) && input.iter().all(|i| self.input_state.contains_key(i)) {
+                    return *repeat;
+                }
+            }
+        }
+        Some(self.repeat.settings)
+    }
+
     fn cancel_pending_tap(&mut self) {
         self.tapping.take();
     }
@@ -353,7 +2397,7 @@ impl InputMapper {
         event_type: KeyEventType,
     ) -> Result<()> {
         for k in key {
-            let event = make_event(k.clone(), time, event_type);
+            let event = make_event(*k, time, event_type);
             self.write_event(&event)?;
         }
         self.generate_sync_event(time)?;
@@ -368,15 +2412,36 @@ impl InputMapper {
 
     fn write_event(&mut self, event: &InputEvent) -> Result<()> {
         log::trace!("OUT: {:?}", event);
-        self.output.write_event(&event)?;
+        self.output.write_event(event)?;
         if let EventCode::EV_KEY(ref key) = event.event_code {
             let event_type = KeyEventType::from_value(event.value);
             match event_type {
-                KeyEventType::Press | KeyEventType::Repeat => {
-                    self.output_keys.insert(key.clone());
+                KeyEventType::Press => {
+                    self.output_keys.insert(*key);
+                    // Only the most recently pressed key repeats, and
+                    // pressing a modifier or a button doesn't interrupt it.
+                    // The delay is measured from now, as replayed events
+                    // that were held back carry the time of the input.
+                    if !is_modifier(key) && !is_button(key) {
+                        self.repeating = self.repeat_settings(*key).map(|settings| RepeatingKey {
+                            key: *key,
+                            interval: settings.interval,
+                            next: timeval_add(&timeval_now(), settings.delay),
+                        });
+                    }
+                }
+                KeyEventType::Repeat => {
+                    self.output_keys.insert(*key);
                 }
                 KeyEventType::Release => {
                     self.output_keys.remove(key);
+                    if self
+                        .repeating
+                        .as_ref()
+                        .is_some_and(|repeating| repeating.key == *key)
+                    {
+                        self.repeating = None;
+                    }
                 }
                 _ => {}
             }
@@ -398,19 +2463,26 @@ fn make_event(key: KeyCode, time: &TimeVal, event_type: KeyEventType) -> InputEv
     InputEvent::new(time, &EventCode::EV_KEY(key), event_type.value())
 }
 
+/// Returns true for the `BTN_*` codes of mice, joysticks and the
+/// like, which the kernel doesn't repeat
+fn is_button(key: &KeyCode) -> bool {
+    let (_, code) = evdev_rs::util::event_code_to_int(&EventCode::EV_KEY(*key));
+    matches!(code, 0x100..=0x151 | 0x220..=0x223 | 0x2c0..=0x2e7)
+}
+
 fn is_modifier(key: &KeyCode) -> bool {
-    match key {
+    matches!(
+        key,
         KeyCode::KEY_FN
-        | KeyCode::KEY_LEFTALT
-        | KeyCode::KEY_RIGHTALT
-        | KeyCode::KEY_LEFTMETA
-        | KeyCode::KEY_RIGHTMETA
-        | KeyCode::KEY_LEFTCTRL
-        | KeyCode::KEY_RIGHTCTRL
-        | KeyCode::KEY_LEFTSHIFT
-        | KeyCode::KEY_RIGHTSHIFT => true,
-        _ => false,
-    }
+            | KeyCode::KEY_LEFTALT
+            | KeyCode::KEY_RIGHTALT
+            | KeyCode::KEY_LEFTMETA
+            | KeyCode::KEY_RIGHTMETA
+            | KeyCode::KEY_LEFTCTRL
+            | KeyCode::KEY_RIGHTCTRL
+            | KeyCode::KEY_LEFTSHIFT
+            | KeyCode::KEY_RIGHTSHIFT
+    )
 }
 
 /// Orders modifier keys ahead of non-modifier keys.
@@ -434,3 +2506,564 @@ fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
 fn modifiers_last(a: &KeyCode, b: &KeyCode) -> Ordering {
     modifiers_first(a, b).reverse()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use KeyCode::{
+        KEY_A, KEY_B, KEY_C, KEY_CAPSLOCK, KEY_ESC, KEY_F1, KEY_G, KEY_J, KEY_K, KEY_LEFTCTRL,
+        KEY_LEFTSHIFT, KEY_RIGHTCTRL, KEY_S,
+    };
+
+    /// Records the events that a mapper writes
+    struct Recorder(Rc<RefCell<Vec<InputEvent>>>);
+
+    impl EventSink for Recorder {
+        fn write_event(&self, event: &InputEvent) -> std::io::Result<()> {
+            self.0.borrow_mut().push(event.clone());
+            std::io::Result::Ok(())
+        }
+    }
+
+    /// Drives an `InputMapper` that has no devices with key events
+    /// at explicit times, given in milliseconds
+    struct TestMapper {
+        mapper: InputMapper,
+        output: Rc<RefCell<Vec<InputEvent>>>,
+    }
+
+    impl TestMapper {
+        fn new(mappings: &str) -> Self {
+            let config: MappingConfig = format!("device_name = \"test\"\n{mappings}")
+                .parse()
+                .unwrap();
+            let output = Rc::new(RefCell::new(vec![]));
+            let mapper = InputMapper::new(
+                vec![],
+                Box::new(Recorder(output.clone())),
+                &config.devices[0],
+                vec![],
+            );
+            Self { mapper, output }
+        }
+
+        fn time(ms: u64) -> TimeVal {
+            timeval_add(&TimeVal::new(1000, 0), Duration::from_millis(ms))
+        }
+
+        fn key(&mut self, key: KeyCode, event_type: KeyEventType, ms: u64) {
+            let event = make_event(key, &Self::time(ms), event_type);
+            self.mapper.process_event(&event).unwrap();
+        }
+
+        fn press(&mut self, key: KeyCode, ms: u64) {
+            self.key(key, KeyEventType::Press, ms);
+        }
+
+        fn release(&mut self, key: KeyCode, ms: u64) {
+            self.key(key, KeyEventType::Release, ms);
+        }
+
+        /// Lets the timers that expire by `ms` fire
+        fn expire(&mut self, ms: u64) {
+            self.mapper.handle_timeouts_at(&Self::time(ms)).unwrap();
+        }
+
+        /// Returns the key events written since the last call,
+        /// as (key, value) pairs
+        fn keys(&mut self) -> Vec<(KeyCode, i32)> {
+            self.output
+                .borrow_mut()
+                .drain(..)
+                .filter_map(|event| match event.event_code {
+                    EventCode::EV_KEY(key) => Some((key, event.value)),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    fn dual_role(interrupt: &str) -> TestMapper {
+        TestMapper::new(&format!(
+            r#"
+            [[dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["KEY_LEFTCTRL"]
+            tap = ["KEY_ESC"]
+            tapping_term_ms = 200
+            hold_on_timeout = true
+            {interrupt}
+            "#
+        ))
+    }
+
+    fn one_shot() -> TestMapper {
+        TestMapper::new(
+            r#"
+            [[one_shot]]
+            input = "KEY_LEFTSHIFT"
+            output = ["KEY_LEFTSHIFT"]
+            tapping_term_ms = 200
+            timeout_ms = 1000
+            "#,
+        )
+    }
+
+    #[test]
+    fn one_shot_applies_to_next_key() {
+        let mut m = one_shot();
+        m.press(KEY_LEFTSHIFT, 0);
+        m.release(KEY_LEFTSHIFT, 50);
+        // Like a regular modifier, it is pressed while held
+        assert_eq!(m.keys(), vec![(KEY_LEFTSHIFT, 1), (KEY_LEFTSHIFT, 0)]);
+        // The modifier is released as soon as it has been applied
+        m.press(KEY_A, 100);
+        assert_eq!(
+            m.keys(),
+            vec![(KEY_LEFTSHIFT, 1), (KEY_A, 1), (KEY_LEFTSHIFT, 0)]
+        );
+        m.release(KEY_A, 150);
+        m.press(KEY_B, 200);
+        assert_eq!(m.keys(), vec![(KEY_A, 0), (KEY_B, 1)]);
+    }
+
+    #[test]
+    fn one_shot_tapped_twice_cancels() {
+        let mut m = one_shot();
+        m.press(KEY_LEFTSHIFT, 0);
+        m.release(KEY_LEFTSHIFT, 50);
+        m.press(KEY_LEFTSHIFT, 100);
+        m.release(KEY_LEFTSHIFT, 150);
+        m.keys();
+        m.press(KEY_A, 200);
+        assert_eq!(m.keys(), vec![(KEY_A, 1)]);
+    }
+
+    #[test]
+    fn one_shot_times_out() {
+        let mut m = one_shot();
+        m.press(KEY_LEFTSHIFT, 0);
+        m.release(KEY_LEFTSHIFT, 50);
+        m.keys();
+        m.press(KEY_A, 1050);
+        assert_eq!(m.keys(), vec![(KEY_A, 1)]);
+    }
+
+    #[test]
+    fn one_shot_held_is_a_modifier() {
+        let mut m = one_shot();
+        m.press(KEY_LEFTSHIFT, 0);
+        m.press(KEY_A, 300);
+        m.release(KEY_A, 350);
+        m.release(KEY_LEFTSHIFT, 400);
+        m.press(KEY_B, 500);
+        assert_eq!(
+            m.keys(),
+            vec![
+                (KEY_LEFTSHIFT, 1),
+                (KEY_A, 1),
+                (KEY_A, 0),
+                (KEY_LEFTSHIFT, 0),
+                (KEY_B, 1)
+            ]
+        );
+    }
+
+    fn tap_dance() -> TestMapper {
+        TestMapper::new(
+            r#"
+            [[tap_dance]]
+            input = "KEY_ESC"
+            tapping_term_ms = 200
+            taps = [
+              { tap = ["KEY_ESC"], hold = ["KEY_LEFTCTRL"] },
+              { tap = ["KEY_CAPSLOCK"] },
+            ]
+            "#,
+        )
+    }
+
+    #[test]
+    fn tap_dance_single_tap() {
+        let mut m = tap_dance();
+        m.press(KEY_ESC, 0);
+        m.release(KEY_ESC, 50);
+        m.expire(249);
+        assert_eq!(m.keys(), vec![]);
+        m.expire(250);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0)]);
+    }
+
+    #[test]
+    fn tap_dance_resolves_before_later_key() {
+        // The timer hasn't fired when A is read
+        let mut m = tap_dance();
+        m.press(KEY_ESC, 0);
+        m.release(KEY_ESC, 50);
+        m.press(KEY_A, 400);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0), (KEY_A, 1)]);
+    }
+
+    #[test]
+    fn tap_dance_double_tap() {
+        let mut m = tap_dance();
+        m.press(KEY_ESC, 0);
+        m.release(KEY_ESC, 50);
+        m.press(KEY_ESC, 100);
+        m.release(KEY_ESC, 150);
+        // There is no third step to wait for
+        assert_eq!(m.keys(), vec![(KEY_CAPSLOCK, 1), (KEY_CAPSLOCK, 0)]);
+    }
+
+    #[test]
+    fn tap_dance_hold() {
+        let mut m = tap_dance();
+        m.press(KEY_ESC, 0);
+        m.expire(200);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 1)]);
+        m.release(KEY_ESC, 300);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 0)]);
+    }
+
+    fn sequence() -> TestMapper {
+        TestMapper::new(
+            r#"
+            [[sequence]]
+            input = ["KEY_J", "KEY_K"]
+            output = ["KEY_ESC"]
+            term_ms = 200
+            "#,
+        )
+    }
+
+    #[test]
+    fn sequence_completed_within_term() {
+        let mut m = sequence();
+        m.press(KEY_J, 0);
+        m.release(KEY_J, 20);
+        assert_eq!(m.keys(), vec![]);
+        m.press(KEY_K, 150);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0)]);
+        // The rest of the sequence key is swallowed
+        m.release(KEY_K, 170);
+        assert_eq!(m.keys(), vec![]);
+    }
+
+    #[test]
+    fn sequence_timeout_replays_keys() {
+        let mut m = sequence();
+        m.press(KEY_J, 0);
+        m.release(KEY_J, 20);
+        m.expire(199);
+        assert_eq!(m.keys(), vec![]);
+        m.expire(200);
+        assert_eq!(m.keys(), vec![(KEY_J, 1), (KEY_J, 0)]);
+    }
+
+    #[test]
+    fn sequence_late_key_is_not_part_of_it() {
+        // The timer hasn't fired when K is read
+        let mut m = sequence();
+        m.press(KEY_J, 0);
+        m.press(KEY_K, 250);
+        assert_eq!(m.keys(), vec![(KEY_J, 1), (KEY_K, 1)]);
+    }
+
+    #[test]
+    fn sequence_broken_by_other_key_replays() {
+        let mut m = sequence();
+        m.press(KEY_J, 0);
+        m.press(KEY_A, 50);
+        assert_eq!(m.keys(), vec![(KEY_J, 1), (KEY_A, 1)]);
+    }
+
+    #[test]
+    fn sequence_keeps_release_of_earlier_key() {
+        let mut m = sequence();
+        m.press(KEY_B, 0);
+        assert_eq!(m.keys(), vec![(KEY_B, 1)]);
+        m.press(KEY_J, 10);
+        m.release(KEY_B, 20);
+        assert_eq!(m.keys(), vec![]);
+        assert!(m.mapper.repeating.is_none());
+        m.press(KEY_K, 50);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0), (KEY_B, 0)]);
+    }
+
+    fn combo() -> TestMapper {
+        TestMapper::new(
+            r#"
+            [[combo]]
+            input = ["KEY_J", "KEY_K"]
+            output = ["KEY_ESC"]
+            term_ms = 50
+            "#,
+        )
+    }
+
+    #[test]
+    fn combo_pressed_together() {
+        let mut m = combo();
+        m.press(KEY_J, 0);
+        assert_eq!(m.keys(), vec![]);
+        m.press(KEY_K, 20);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1)]);
+        // The output is held until the first combo key is released
+        m.release(KEY_K, 100);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 0)]);
+        m.release(KEY_J, 120);
+        assert_eq!(m.keys(), vec![]);
+    }
+
+    #[test]
+    fn partial_combo_released_replays_keys() {
+        let mut m = combo();
+        m.press(KEY_J, 0);
+        m.release(KEY_J, 30);
+        assert_eq!(m.keys(), vec![(KEY_J, 1), (KEY_J, 0)]);
+    }
+
+    #[test]
+    fn partial_combo_timeout_replays_keys() {
+        let mut m = combo();
+        m.press(KEY_J, 0);
+        m.expire(50);
+        assert_eq!(m.keys(), vec![(KEY_J, 1)]);
+        m.press(KEY_K, 60);
+        m.release(KEY_J, 70);
+        m.release(KEY_K, 80);
+        assert_eq!(m.keys(), vec![(KEY_K, 1), (KEY_J, 0), (KEY_K, 0)]);
+    }
+
+    fn leader() -> TestMapper {
+        TestMapper::new(
+            r#"
+            [[leader]]
+            input = "KEY_RIGHTCTRL"
+            timeout_ms = 1000
+
+            [[leader.sequence]]
+            keys = ["KEY_G", "KEY_S"]
+            action = ["KEY_F1"]
+
+            [[leader.sequence]]
+            keys = ["KEY_C"]
+            action = ["KEY_LEFTCTRL", "KEY_C"]
+            "#,
+        )
+    }
+
+    #[test]
+    fn leader_sequence_runs_action() {
+        let mut m = leader();
+        m.press(KEY_RIGHTCTRL, 0);
+        m.release(KEY_RIGHTCTRL, 10);
+        m.press(KEY_G, 100);
+        m.release(KEY_G, 110);
+        assert_eq!(m.keys(), vec![]);
+        m.press(KEY_S, 200);
+        m.release(KEY_S, 210);
+        assert_eq!(m.keys(), vec![(KEY_F1, 1), (KEY_F1, 0)]);
+    }
+
+    #[test]
+    fn leader_pressed_again_replays_captured_keys() {
+        let mut m = leader();
+        m.press(KEY_RIGHTCTRL, 0);
+        m.release(KEY_RIGHTCTRL, 10);
+        m.press(KEY_G, 100);
+        m.release(KEY_G, 110);
+        m.press(KEY_RIGHTCTRL, 200);
+        m.release(KEY_RIGHTCTRL, 210);
+        assert_eq!(m.keys(), vec![(KEY_G, 1), (KEY_G, 0)]);
+        // The second leader press starts a new capture
+        m.press(KEY_C, 300);
+        m.release(KEY_C, 310);
+        assert_eq!(
+            m.keys(),
+            vec![(KEY_LEFTCTRL, 1), (KEY_C, 1), (KEY_LEFTCTRL, 0), (KEY_C, 0)]
+        );
+    }
+
+    #[test]
+    fn leader_timeout_replays_captured_keys() {
+        let mut m = leader();
+        m.press(KEY_RIGHTCTRL, 0);
+        m.release(KEY_RIGHTCTRL, 10);
+        m.press(KEY_G, 100);
+        m.release(KEY_G, 110);
+        m.expire(1100);
+        assert_eq!(m.keys(), vec![(KEY_G, 1), (KEY_G, 0)]);
+    }
+
+    #[test]
+    fn dual_role_released_within_term_taps() {
+        let mut m = dual_role("");
+        m.press(KEY_CAPSLOCK, 0);
+        m.release(KEY_CAPSLOCK, 199);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0)]);
+    }
+
+    #[test]
+    fn dual_role_released_after_term_holds() {
+        // The timer hasn't fired when the release is read
+        let mut m = dual_role("");
+        m.press(KEY_CAPSLOCK, 0);
+        m.release(KEY_CAPSLOCK, 201);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 1), (KEY_LEFTCTRL, 0)]);
+    }
+
+    #[test]
+    fn dual_role_holds_when_timer_fires() {
+        let mut m = dual_role("");
+        m.press(KEY_CAPSLOCK, 0);
+        m.expire(199);
+        assert_eq!(m.keys(), vec![]);
+        m.expire(200);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 1)]);
+        m.release(KEY_CAPSLOCK, 500);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 0)]);
+    }
+
+    #[test]
+    fn dual_role_other_key_press_holds() {
+        let mut m = dual_role("");
+        m.press(KEY_CAPSLOCK, 0);
+        m.press(KEY_A, 50);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 1), (KEY_A, 1)]);
+    }
+
+    #[test]
+    fn permissive_hold_buffers_rolled_keys() {
+        let mut m = dual_role(r#"interrupt = "permissive-hold""#);
+        m.press(KEY_CAPSLOCK, 0);
+        m.press(KEY_A, 50);
+        assert_eq!(m.keys(), vec![]);
+        m.release(KEY_CAPSLOCK, 100);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0), (KEY_A, 1)]);
+        m.release(KEY_A, 150);
+        assert_eq!(m.keys(), vec![(KEY_A, 0)]);
+    }
+
+    #[test]
+    fn permissive_hold_holds_on_nested_tap() {
+        let mut m = dual_role(r#"interrupt = "permissive-hold""#);
+        m.press(KEY_CAPSLOCK, 0);
+        m.press(KEY_A, 50);
+        m.release(KEY_A, 80);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 1), (KEY_A, 1), (KEY_A, 0)]);
+    }
+
+    #[test]
+    fn permissive_hold_ignores_keys_already_down() {
+        let mut m = dual_role(r#"interrupt = "permissive-hold""#);
+        m.press(KEY_B, 0);
+        m.press(KEY_CAPSLOCK, 10);
+        m.release(KEY_B, 50);
+        assert_eq!(m.keys(), vec![(KEY_B, 1), (KEY_B, 0)]);
+        m.release(KEY_CAPSLOCK, 100);
+        assert_eq!(m.keys(), vec![(KEY_ESC, 1), (KEY_ESC, 0)]);
+    }
+
+    #[test]
+    fn tap_preferred_waits_for_term() {
+        let mut m = dual_role(r#"interrupt = "tap-preferred""#);
+        m.press(KEY_CAPSLOCK, 0);
+        m.press(KEY_A, 50);
+        m.release(KEY_A, 80);
+        assert_eq!(m.keys(), vec![]);
+        m.expire(200);
+        assert_eq!(m.keys(), vec![(KEY_LEFTCTRL, 1), (KEY_A, 1), (KEY_A, 0)]);
+    }
+
+    fn abs_info(minimum: i32, maximum: i32, fuzz: i32, flat: i32) -> AbsInfo {
+        AbsInfo {
+            value: 0,
+            minimum,
+            maximum,
+            fuzz,
+            flat,
+            resolution: 0,
+        }
+    }
+
+    fn trigger(info: AbsInfo, direction: AxisDirection, threshold: Option<i32>) -> AbsTrigger {
+        AbsTrigger::with_abs_info(&info, EV_ABS::ABS_X, direction, threshold, vec![])
+    }
+
+    #[test]
+    fn explicit_threshold_below_center() {
+        let mut pedal = trigger(abs_info(0, 255, 0, 0), AxisDirection::Positive, Some(50));
+        assert!(!pedal.update(49));
+        assert!(pedal.update(50));
+        assert!(pedal.active);
+        assert!(pedal.update(0));
+        assert!(!pedal.active);
+    }
+
+    #[test]
+    fn explicit_threshold_ignores_flat() {
+        let mut pedal = trigger(abs_info(0, 255, 0, 0), AxisDirection::Positive, Some(50));
+        let mut flat_pedal = trigger(abs_info(0, 255, 0, 200), AxisDirection::Positive, Some(50));
+        assert_eq!(pedal.press_at, flat_pedal.press_at);
+        assert!(flat_pedal.update(50));
+        // The hysteresis is limited so that the resting pedal releases
+        assert!(flat_pedal.update(0));
+        assert!(!pedal.update(0));
+    }
+
+    #[test]
+    fn default_threshold_of_hat() {
+        let mut left = trigger(abs_info(-1, 1, 0, 0), AxisDirection::Negative, None);
+        let mut right = trigger(abs_info(-1, 1, 0, 0), AxisDirection::Positive, None);
+        assert!(!left.update(0));
+        assert!(!right.update(0));
+        assert!(left.update(-1));
+        assert!(!right.update(-1));
+        assert!(right.update(1));
+        assert!(left.update(1));
+    }
+
+    #[test]
+    fn default_threshold_is_halfway() {
+        let mut stick = trigger(abs_info(-1000, 1000, 0, 0), AxisDirection::Positive, None);
+        assert!(!stick.update(499));
+        assert!(stick.update(500));
+        let mut stick = trigger(abs_info(-1000, 1000, 0, 0), AxisDirection::Negative, None);
+        assert!(!stick.update(-499));
+        assert!(stick.update(-500));
+    }
+
+    #[test]
+    fn default_threshold_is_outside_flat() {
+        let mut stick = trigger(abs_info(-10, 10, 0, 8), AxisDirection::Positive, None);
+        assert!(!stick.update(8));
+        assert!(stick.update(9));
+    }
+
+    #[test]
+    fn hysteresis_from_fuzz() {
+        let mut axis = trigger(abs_info(0, 1000, 16, 4), AxisDirection::Positive, Some(100));
+        assert!(axis.update(100));
+        assert!(!axis.update(85));
+        assert!(!axis.update(84));
+        assert!(axis.update(83));
+        // Pressing again needs the full threshold
+        assert!(!axis.update(99));
+        assert!(axis.update(100));
+    }
+
+    #[test]
+    fn hysteresis_negative_direction() {
+        let mut axis = trigger(
+            abs_info(-100, 100, 0, 10),
+            AxisDirection::Negative,
+            Some(-50),
+        );
+        assert!(axis.update(-50));
+        assert!(!axis.update(-40));
+        assert!(axis.update(-39));
+    }
+}
@@ -0,0 +1,303 @@
+use crate::mapping::{ConfigError, KeyCode, MacroStep};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// The keyboard layouts whose character tables are built in
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LayoutName {
+    #[default]
+    Us,
+}
+
+/// How to type characters that have no key in the layout
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodeInput {
+    /// Press CTRL+SHIFT+U, type the hex code point and then SPACE,
+    /// as understood by GTK and IBus
+    #[default]
+    CtrlShiftU,
+    /// Such characters are a configuration error
+    None,
+}
+
+/// Maps characters to the keys that produce them
+#[derive(Debug, Clone, Default)]
+pub struct TextLayout {
+    pub layout: LayoutName,
+    /// Characters that are produced by pressing these keys together,
+    /// taking precedence over the built in layout
+    pub overrides: HashMap<char, Vec<KeyCode>>,
+    pub unicode: UnicodeInput,
+}
+
+impl TextLayout {
+    /// Compute the macro steps that type `text`
+    pub fn text_to_steps(&self, text: &str) -> Result<Vec<MacroStep>, ConfigError> {
+        let mut steps = vec![];
+        for c in text.chars() {
+            self.char_to_steps(c, &mut steps)?;
+        }
+        Ok(steps)
+    }
+
+    fn char_to_steps(&self, c: char, steps: &mut Vec<MacroStep>) -> Result<(), ConfigError> {
+        if let Some(keys) = self.keys_for(c) {
+            push_chord(&keys, steps);
+            return Ok(());
+        }
+
+        match self.unicode {
+            UnicodeInput::CtrlShiftU => {
+                // These are typed according to the layout too, as the
+                // input method acts on the characters that they produce
+                let keys_for = |c| {
+                    self.keys_for(c)
+                        .expect("u and hex digits are in the layout")
+                };
+                steps.push(MacroStep::Press(KeyCode::KEY_LEFTCTRL));
+                steps.push(MacroStep::Press(KeyCode::KEY_LEFTSHIFT));
+                push_chord(&keys_for('u'), steps);
+                steps.push(MacroStep::Release(KeyCode::KEY_LEFTSHIFT));
+                steps.push(MacroStep::Release(KeyCode::KEY_LEFTCTRL));
+                for digit in format!("{:x}", c as u32).chars() {
+                    push_chord(&keys_for(digit), steps);
+                }
+                steps.push(MacroStep::Tap(KeyCode::KEY_SPACE));
+                Ok(())
+            }
+            UnicodeInput::None => Err(ConfigError::UntypeableCharacter(c)),
+        }
+    }
+
+    /// Returns the keys that are pressed together to type `c`.
+    /// Overrides take precedence over the layout, and an uppercase
+    /// letter whose lowercase form is overridden is typed by adding
+    /// shift to the override.
+    fn keys_for(&self, c: char) -> Option<Vec<KeyCode>> {
+        if let Some(keys) = self.overrides.get(&c) {
+            return Some(keys.clone());
+        }
+
+        let mut lower = c.to_lowercase();
+        if let (Some(lower), None) = (lower.next(), lower.next()) {
+            if lower != c {
+                if let Some(keys) = self.overrides.get(&lower) {
+                    let mut keys = keys.clone();
+                    if !keys.contains(&KeyCode::KEY_LEFTSHIFT) {
+                        keys.insert(0, KeyCode::KEY_LEFTSHIFT);
+                    }
+                    return Some(keys);
+                }
+            }
+        }
+
+        let (key, shift) = match self.layout {
+            LayoutName::Us => us_layout(c)?,
+        };
+        Some(if shift {
+            vec![KeyCode::KEY_LEFTSHIFT, key]
+        } else {
+            vec![key]
+        })
+    }
+}
+
+/// Presses `keys` in order, then releases them in reverse order
+fn push_chord(keys: &[KeyCode], steps: &mut Vec<MacroStep>) {
+    match keys {
+        [key] => steps.push(MacroStep::Tap(*key)),
+        _ => {
+            steps.extend(keys.iter().map(|k| MacroStep::Press(*k)));
+            steps.extend(keys.iter().rev().map(|k| MacroStep::Release(*k)));
+        }
+    }
+}
+
+/// Returns the key, and whether shift must be held, to produce
+/// `c` with a US keyboard layout
+fn us_layout(c: char) -> Option<(KeyCode, bool)> {
+    use KeyCode::*;
+    let unshifted = |key| Some((key, false));
+    let shifted = |key| Some((key, true));
+
+    if c.is_ascii_uppercase() {
+        return us_layout(c.to_ascii_lowercase()).map(|(key, _)| (key, true));
+    }
+
+    match c {
+        'a' => unshifted(KEY_A),
+        'b' => unshifted(KEY_B),
+        'c' => unshifted(KEY_C),
+        'd' => unshifted(KEY_D),
+        'e' => unshifted(KEY_E),
+        'f' => unshifted(KEY_F),
+        'g' => unshifted(KEY_G),
+        'h' => unshifted(KEY_H),
+        'i' => unshifted(KEY_I),
+        'j' => unshifted(KEY_J),
+        'k' => unshifted(KEY_K),
+        'l' => unshifted(KEY_L),
+        'm' => unshifted(KEY_M),
+        'n' => unshifted(KEY_N),
+        'o' => unshifted(KEY_O),
+        'p' => unshifted(KEY_P),
+        'q' => unshifted(KEY_Q),
+        'r' => unshifted(KEY_R),
+        's' => unshifted(KEY_S),
+        't' => unshifted(KEY_T),
+        'u' => unshifted(KEY_U),
+        'v' => unshifted(KEY_V),
+        'w' => unshifted(KEY_W),
+        'x' => unshifted(KEY_X),
+        'y' => unshifted(KEY_Y),
+        'z' => unshifted(KEY_Z),
+        '1' => unshifted(KEY_1),
+        '2' => unshifted(KEY_2),
+        '3' => unshifted(KEY_3),
+        '4' => unshifted(KEY_4),
+        '5' => unshifted(KEY_5),
+        '6' => unshifted(KEY_6),
+        '7' => unshifted(KEY_7),
+        '8' => unshifted(KEY_8),
+        '9' => unshifted(KEY_9),
+        '0' => unshifted(KEY_0),
+        '!' => shifted(KEY_1),
+        '@' => shifted(KEY_2),
+        '#' => shifted(KEY_3),
+        '$' => shifted(KEY_4),
+        '%' => shifted(KEY_5),
+        '^' => shifted(KEY_6),
+        '&' => shifted(KEY_7),
+        '*' => shifted(KEY_8),
+        '(' => shifted(KEY_9),
+        ')' => shifted(KEY_0),
+        ' ' => unshifted(KEY_SPACE),
+        '\n' => unshifted(KEY_ENTER),
+        '\t' => unshifted(KEY_TAB),
+        '-' => unshifted(KEY_MINUS),
+        '_' => shifted(KEY_MINUS),
+        '=' => unshifted(KEY_EQUAL),
+        '+' => shifted(KEY_EQUAL),
+        '[' => unshifted(KEY_LEFTBRACE),
+        '{' => shifted(KEY_LEFTBRACE),
+        ']' => unshifted(KEY_RIGHTBRACE),
+        '}' => shifted(KEY_RIGHTBRACE),
+        '\\' => unshifted(KEY_BACKSLASH),
+        '|' => shifted(KEY_BACKSLASH),
+        ';' => unshifted(KEY_SEMICOLON),
+        ':' => shifted(KEY_SEMICOLON),
+        '\'' => unshifted(KEY_APOSTROPHE),
+        '"' => shifted(KEY_APOSTROPHE),
+        '`' => unshifted(KEY_GRAVE),
+        '~' => shifted(KEY_GRAVE),
+        ',' => unshifted(KEY_COMMA),
+        '<' => shifted(KEY_COMMA),
+        '.' => unshifted(KEY_DOT),
+        '>' => shifted(KEY_DOT),
+        '/' => unshifted(KEY_SLASH),
+        '?' => shifted(KEY_SLASH),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyCode::*;
+    use MacroStep::*;
+
+    fn qwertz() -> TextLayout {
+        TextLayout {
+            overrides: [('z', vec![KEY_Y]), ('y', vec![KEY_Z])].into(),
+            ..TextLayout::default()
+        }
+    }
+
+    #[test]
+    fn us_layout_text() {
+        let steps = TextLayout::default().text_to_steps("aB!").unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                Tap(KEY_A),
+                Press(KEY_LEFTSHIFT),
+                Press(KEY_B),
+                Release(KEY_B),
+                Release(KEY_LEFTSHIFT),
+                Press(KEY_LEFTSHIFT),
+                Press(KEY_1),
+                Release(KEY_1),
+                Release(KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn override_applies_to_uppercase() {
+        let steps = qwertz().text_to_steps("zZ").unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                Tap(KEY_Y),
+                Press(KEY_LEFTSHIFT),
+                Press(KEY_Y),
+                Release(KEY_Y),
+                Release(KEY_LEFTSHIFT),
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_override_beats_uppercase_fallback() {
+        let layout = TextLayout {
+            overrides: [('z', vec![KEY_Y]), ('Z', vec![KEY_F1])].into(),
+            ..TextLayout::default()
+        };
+        assert_eq!(layout.text_to_steps("Z").unwrap(), vec![Tap(KEY_F1)]);
+    }
+
+    #[test]
+    fn unicode_uses_overrides() {
+        let layout = TextLayout {
+            overrides: [
+                ('u', vec![KEY_F]),
+                ('e', vec![KEY_D]),
+                ('9', vec![KEY_LEFTSHIFT, KEY_9]),
+            ]
+            .into(),
+            ..TextLayout::default()
+        };
+        // U+00E9 is typed as the digits e and 9
+        let steps = layout.text_to_steps("\u{e9}").unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                Press(KEY_LEFTCTRL),
+                Press(KEY_LEFTSHIFT),
+                Tap(KEY_F),
+                Release(KEY_LEFTSHIFT),
+                Release(KEY_LEFTCTRL),
+                Tap(KEY_D),
+                Press(KEY_LEFTSHIFT),
+                Press(KEY_9),
+                Release(KEY_9),
+                Release(KEY_LEFTSHIFT),
+                Tap(KEY_SPACE),
+            ]
+        );
+    }
+
+    #[test]
+    fn untypeable_without_unicode() {
+        let layout = TextLayout {
+            unicode: UnicodeInput::None,
+            ..TextLayout::default()
+        };
+        assert!(matches!(
+            layout.text_to_steps("\u{e9}"),
+            Err(ConfigError::UntypeableCharacter('\u{e9}'))
+        ));
+    }
+}